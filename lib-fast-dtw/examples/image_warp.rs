@@ -1,40 +1,31 @@
-#![feature(min_const_generics)]
+#![allow(clippy::needless_range_loop)]
 
-use fast_dtw::{downsample_fns, loss_fns, DtwSolver};
+use fast_dtw::{downsample_fns, loss_fns, DtwWorkspace};
 use image::GenericImageView;
-use std::thread;
 use std::time::SystemTime;
 
-const STACK_SIZE: usize = 128 * 1024 * 1024;
 const IMG_HEIGHT: usize = 512;
 const IMG_WIDTH: usize = 512;
-const MAX_PATH_SIZE: usize = 1025;
+const MAX_PATH_SIZE: usize = 2 * IMG_WIDTH - 1;
 
-fn main() {
-    thread::Builder::new()
-        .stack_size(STACK_SIZE)
-        .spawn(run)
-        .unwrap()
-        .join()
-        .unwrap();
-}
+// Built once and reused for every row, rather than building new maps for
+// each one. Its maps are far too big for the stack, so it lives in a static.
+static mut WORKSPACE: DtwWorkspace<[f32; 3], IMG_WIDTH, IMG_WIDTH, MAX_PATH_SIZE> =
+    DtwWorkspace::new(downsample_fns::mean, loss_fns::euclidean::<3>);
 
-fn run() {
+fn main() {
     let left_img = load_image::<IMG_HEIGHT, IMG_WIDTH>("./examples/pentagon-left.gif");
     let right_img = load_image::<IMG_HEIGHT, IMG_WIDTH>("./examples/pentagon-right.gif");
-    let mut warped_img = [[[0f32; 3]; IMG_WIDTH]; IMG_HEIGHT];
-    let mut depth_img = [[0f32; IMG_WIDTH]; IMG_HEIGHT];
+    let mut warped_img = vec![[[0f32; 3]; IMG_WIDTH]; IMG_HEIGHT];
+    let mut depth_img = vec![[0f32; IMG_WIDTH]; IMG_HEIGHT];
+
+    // Nothing else touches the workspace
+    let workspace = unsafe { &mut *std::ptr::addr_of_mut!(WORKSPACE) };
+    workspace.limit_downsamples(0);
 
     let start = SystemTime::now();
     for y in 0..IMG_HEIGHT {
-        let path = DtwSolver::<[f32; 3], IMG_WIDTH, MAX_PATH_SIZE>::new(
-            &left_img[y],
-            &right_img[y],
-            downsample_fns::mean,
-            loss_fns::euclidean::<3>,
-        )
-        .limit_downsamples(0)
-        .solve();
+        let path = workspace.solve(&left_img[y], &right_img[y]);
 
         warped_img[y] = path.warp(left_img[y]);
         depth_img[y] = path.get_disparity();
@@ -44,24 +35,14 @@ fn run() {
         SystemTime::now().duration_since(start).unwrap().as_nanos() as f32 / 1000.0
     );
 
-    save_rgb_image::<IMG_HEIGHT, IMG_WIDTH>("./examples/output/pentagon-warped.bmp", warped_img);
-    save_gray_image::<IMG_HEIGHT, IMG_WIDTH>("./examples/output/pentagon-depth.bmp", depth_img);
+    save_rgb_image("./examples/output/pentagon-warped.bmp", &warped_img);
+    save_gray_image("./examples/output/pentagon-depth.bmp", &depth_img);
 }
 
-fn derive_signal<const N: usize>(sig: &[[f32; 3]; N]) -> [[f32; 3]; N] {
-    let mut derivative = [[0f32; 3]; N];
-
-    for t in 0..N - 1 {
-        derivative[t][0] = sig[t + 1][0] - sig[t][0];
-        derivative[t][1] = sig[t + 1][1] - sig[t][1];
-        derivative[t][2] = sig[t + 1][2] - sig[t][2];
-    }
-
-    derivative
-}
-
-fn load_image<const H: usize, const W: usize>(filepath: &'static str) -> [[[f32; 3]; W]; H] {
-    let mut img = [[[0f32; 3]; W]; H];
+// Images are kept on the heap a row at a time, since a whole one doesn't fit
+// on the stack either
+fn load_image<const H: usize, const W: usize>(filepath: &'static str) -> Vec<[[f32; 3]; W]> {
+    let mut img = vec![[[0f32; 3]; W]; H];
     let file_img = image::open(filepath).unwrap();
 
     println!(
@@ -85,11 +66,11 @@ fn load_image<const H: usize, const W: usize>(filepath: &'static str) -> [[[f32;
     img
 }
 
-fn save_gray_image<const H: usize, const W: usize>(filepath: &'static str, img: [[f32; W]; H]) {
-    let mut rgb_img = [[[0f32; 3]; W]; H];
+fn save_gray_image<const W: usize>(filepath: &'static str, img: &[[f32; W]]) {
+    let mut rgb_img = vec![[[0f32; 3]; W]; img.len()];
 
     let mut max = 0f32;
-    for y in 0..H {
+    for y in 0..img.len() {
         for x in 0..W {
             if img[y][x] > max {
                 max = img[y][x];
@@ -97,7 +78,7 @@ fn save_gray_image<const H: usize, const W: usize>(filepath: &'static str, img:
         }
     }
 
-    for y in 0..H {
+    for y in 0..img.len() {
         for x in 0..W {
             let pix = img[y][x] / max;
             rgb_img[y][x][0] = pix;
@@ -106,11 +87,11 @@ fn save_gray_image<const H: usize, const W: usize>(filepath: &'static str, img:
         }
     }
 
-    save_rgb_image(filepath, rgb_img);
+    save_rgb_image(filepath, &rgb_img);
 }
 
-fn save_rgb_image<const H: usize, const W: usize>(filepath: &'static str, img: [[[f32; 3]; W]; H]) {
-    let mut img_buf = image::ImageBuffer::new(W as u32, H as u32);
+fn save_rgb_image<const W: usize>(filepath: &'static str, img: &[[[f32; 3]; W]]) {
+    let mut img_buf = image::ImageBuffer::new(W as u32, img.len() as u32);
 
     for (img_x, img_y, pixel) in img_buf.enumerate_pixels_mut() {
         let x = img_x as usize;
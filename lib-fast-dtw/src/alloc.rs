@@ -1,7 +1,15 @@
 #[inline]
+#[allow(clippy::uninit_assumed_init)]
 pub fn alloc<T>(zero_mem: bool) -> T {
   match zero_mem {
     true => unsafe { core::mem::zeroed() },
     false => unsafe { core::mem::MaybeUninit::uninit().assume_init() },
   }
 }
+
+/// Zeroed memory, like `alloc(true)`, but usable in a `const fn` so that
+/// whatever it's built into can go in a `static`.
+#[inline]
+pub const fn zeroed<T>() -> T {
+  unsafe { core::mem::zeroed() }
+}
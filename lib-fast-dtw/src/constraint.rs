@@ -0,0 +1,98 @@
+/// A global limit on how far the warping path may stray from the diagonal.
+/// It applies to every level of the downsample pyramid, and cells outside of
+/// it are never evaluated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GlobalConstraint {
+  /// The Sakoe-Chiba band, which only allows cells within `width` samples of
+  /// the diagonal (`|y - x| <= width` for signals of equal length). For
+  /// stereo matching this is the maximum disparity.
+  SakoeChibaBand { width: usize },
+
+  /// The Itakura parallelogram, which bounds the slope of the path globally.
+  /// Its sides leave both corners of the grid at `max_slope` and
+  /// `1 / max_slope` times the slope of the diagonal, so no stretch of the
+  /// path can compress or expand time by more than `max_slope`. Slopes
+  /// below 1 are treated as 1.
+  Itakura { max_slope: f32 },
+}
+impl GlobalConstraint {
+  /// The equivalent constraint on signals that have been downsampled until
+  /// each of their samples spans `scale` samples of the originals.
+  #[inline]
+  pub(crate) fn downsample(&self, scale: usize) -> GlobalConstraint {
+    match *self {
+      GlobalConstraint::SakoeChibaBand { width } => GlobalConstraint::SakoeChibaBand {
+        width: width / scale,
+      },
+      // The slope doesn't change when both signals shrink by the same factor
+      GlobalConstraint::Itakura { max_slope } => GlobalConstraint::Itakura { max_slope },
+    }
+  }
+
+  /// The inclusive range of columns the constraint allows in row `y` of a
+  /// `y_size` by `x_size` grid.
+  #[inline]
+  pub(crate) fn row(&self, y: usize, y_size: usize, x_size: usize) -> (usize, usize) {
+    let (min_x, max_x) = self.raw_row(y, y_size, x_size);
+
+    // If the diagonal is steeper than the constraint is wide, neighbouring
+    // rows don't meet. Stretch the row back to the left until it at least
+    // touches the previous one diagonally, so the path can always step from
+    // one onto the next.
+    match y > 0 {
+      true => {
+        let prev_max_x = self.raw_row(y - 1, y_size, x_size).1;
+        (core::cmp::min(min_x, prev_max_x + 1), max_x)
+      }
+      false => (min_x, max_x),
+    }
+  }
+
+  #[inline]
+  fn raw_row(&self, y: usize, y_size: usize, x_size: usize) -> (usize, usize) {
+    match *self {
+      GlobalConstraint::SakoeChibaBand { width } => {
+        // With signals of different lengths the diagonal is stretched to
+        // still meet the far corner.
+        let center = match y_size {
+          1 => 0,
+          _ => y * (x_size - 1) / (y_size - 1),
+        };
+        (
+          center.saturating_sub(width),
+          core::cmp::min(center + width, x_size - 1),
+        )
+      }
+      // A single row or column leaves the path nowhere else to go
+      GlobalConstraint::Itakura { .. } if y_size == 1 || x_size == 1 => (0, x_size - 1),
+      GlobalConstraint::Itakura { max_slope } => {
+        let (y_last, x_last) = ((y_size - 1) as f32, (x_size - 1) as f32);
+        let max_slope = libm::fmaxf(max_slope, 1f32);
+        let diagonal_slope = x_last / y_last;
+        let steep = diagonal_slope * max_slope;
+        let shallow = diagonal_slope / max_slope;
+
+        let from_start = y as f32;
+        let from_end = y_last - from_start;
+        let min_x = libm::fmaxf(shallow * from_start, x_last - steep * from_end);
+        let max_x = libm::fminf(steep * from_start, x_last - shallow * from_end);
+        let min_x = libm::ceilf(libm::fmaxf(min_x, 0f32)) as usize;
+        let max_x = libm::floorf(libm::fminf(max_x, x_last)) as usize;
+
+        // At a slope of 1 both sides run along the diagonal, so rounding can
+        // push a row past the last column, or miss the corners altogether
+        let min_x = match y {
+          0 => 0,
+          _ => core::cmp::min(min_x, x_size - 1),
+        };
+        let max_x = match y + 1 == y_size {
+          true => x_size - 1,
+          false => max_x,
+        };
+
+        // Rounding can leave a row near the corners empty
+        (min_x, core::cmp::max(min_x, max_x))
+      }
+    }
+  }
+}
@@ -0,0 +1,230 @@
+/// The type that losses are measured and added up in.
+///
+/// Costs never go past `INFINITY`, which stands in for the loss of a cell
+/// that can't be reached. Integer and fixed-point costs saturate rather than
+/// overflow, so a solve only needs integer arithmetic and can run on
+/// hardware without an FPU.
+pub trait Cost: Copy + PartialOrd + core::fmt::Debug {
+  const ZERO: Self;
+  const INFINITY: Self;
+
+  /// The sum of two costs, saturating at `INFINITY`.
+  fn plus(self, other: Self) -> Self;
+
+  /// The cost multiplied by a step weight, saturating at `INFINITY`.
+  fn times(self, weight: u32) -> Self;
+
+  /// The cost divided by the total weight of a path. Integer costs round
+  /// down.
+  fn divided_by(self, weight: u32) -> Self;
+
+  /// The cost multiplied by a fractional weight, like a `PhaseWeight`,
+  /// saturating at `INFINITY`. Integer costs round down.
+  fn scaled(self, factor: f32) -> Self;
+
+  /// The cost as a float, for comparing costs by their ratio.
+  fn to_f32(self) -> f32;
+}
+
+impl Cost for f32 {
+  const INFINITY: f32 = f32::MAX;
+  const ZERO: f32 = 0f32;
+
+  #[inline]
+  fn plus(self, other: f32) -> f32 {
+    f32::min(self + other, f32::MAX)
+  }
+
+  #[inline]
+  fn times(self, weight: u32) -> f32 {
+    f32::min(self * weight as f32, f32::MAX)
+  }
+
+  #[inline]
+  fn divided_by(self, weight: u32) -> f32 {
+    self / weight as f32
+  }
+
+  #[inline]
+  fn scaled(self, factor: f32) -> f32 {
+    f32::min(self * factor, f32::MAX)
+  }
+
+  #[inline]
+  fn to_f32(self) -> f32 {
+    self
+  }
+}
+
+impl Cost for f64 {
+  const INFINITY: f64 = f64::MAX;
+  const ZERO: f64 = 0f64;
+
+  #[inline]
+  fn plus(self, other: f64) -> f64 {
+    f64::min(self + other, f64::MAX)
+  }
+
+  #[inline]
+  fn times(self, weight: u32) -> f64 {
+    f64::min(self * weight as f64, f64::MAX)
+  }
+
+  #[inline]
+  fn divided_by(self, weight: u32) -> f64 {
+    self / weight as f64
+  }
+
+  #[inline]
+  fn scaled(self, factor: f32) -> f64 {
+    f64::min(self * factor as f64, f64::MAX)
+  }
+
+  #[inline]
+  fn to_f32(self) -> f32 {
+    self as f32
+  }
+}
+
+impl Cost for u32 {
+  const INFINITY: u32 = u32::MAX;
+  const ZERO: u32 = 0;
+
+  #[inline]
+  fn plus(self, other: u32) -> u32 {
+    self.saturating_add(other)
+  }
+
+  #[inline]
+  fn times(self, weight: u32) -> u32 {
+    self.saturating_mul(weight)
+  }
+
+  #[inline]
+  fn divided_by(self, weight: u32) -> u32 {
+    self / weight
+  }
+
+  #[inline]
+  fn scaled(self, factor: f32) -> u32 {
+    // Float to integer casts saturate
+    (self as f64 * factor as f64) as u32
+  }
+
+  #[inline]
+  fn to_f32(self) -> f32 {
+    self as f32
+  }
+}
+
+impl Cost for i32 {
+  const INFINITY: i32 = i32::MAX;
+  const ZERO: i32 = 0;
+
+  #[inline]
+  fn plus(self, other: i32) -> i32 {
+    self.saturating_add(other)
+  }
+
+  #[inline]
+  fn times(self, weight: u32) -> i32 {
+    self.saturating_mul(weight as i32)
+  }
+
+  #[inline]
+  fn divided_by(self, weight: u32) -> i32 {
+    self / weight as i32
+  }
+
+  #[inline]
+  fn scaled(self, factor: f32) -> i32 {
+    (self as f64 * factor as f64) as i32
+  }
+
+  #[inline]
+  fn to_f32(self) -> f32 {
+    self as f32
+  }
+}
+
+/// A Q16.16 fixed-point number: the raw `i32` holds 16 bits of integer and
+/// 16 bits of fraction. Costs in it only take integer arithmetic to add up,
+/// while keeping a fraction of a sample's worth of precision.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub struct Q16(pub i32);
+impl Q16 {
+  pub const ONE: Q16 = Q16(1 << 16);
+
+  /// The fixed-point value of a whole number, saturating if it doesn't fit
+  /// in 16 bits.
+  #[inline]
+  pub const fn from_int(value: i32) -> Q16 {
+    Q16(value.saturating_mul(1 << 16))
+  }
+
+  #[inline]
+  pub fn from_f32(value: f32) -> Q16 {
+    Q16((value * 65536f32) as i32)
+  }
+
+  #[inline]
+  pub fn to_f32(self) -> f32 {
+    self.0 as f32 / 65536f32
+  }
+}
+
+impl Cost for Q16 {
+  const INFINITY: Q16 = Q16(i32::MAX);
+  const ZERO: Q16 = Q16(0);
+
+  #[inline]
+  fn plus(self, other: Q16) -> Q16 {
+    Q16(self.0.saturating_add(other.0))
+  }
+
+  #[inline]
+  fn times(self, weight: u32) -> Q16 {
+    Q16(self.0.saturating_mul(weight as i32))
+  }
+
+  #[inline]
+  fn divided_by(self, weight: u32) -> Q16 {
+    Q16(self.0 / weight as i32)
+  }
+
+  #[inline]
+  fn scaled(self, factor: f32) -> Q16 {
+    Q16((self.0 as f64 * factor as f64) as i32)
+  }
+
+  #[inline]
+  fn to_f32(self) -> f32 {
+    Q16::to_f32(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn saturates_at_infinity() {
+    assert_eq!(<f32 as Cost>::INFINITY, f32::MAX.plus(f32::MAX));
+    assert_eq!(<f32 as Cost>::INFINITY, f32::MAX.times(2));
+    assert_eq!(u32::INFINITY, (u32::MAX - 1).plus(5));
+    assert_eq!(<i32 as Cost>::INFINITY, (i32::MAX / 2).times(3));
+    assert_eq!(
+      Q16::INFINITY,
+      Q16::from_int(30000).plus(Q16::from_int(30000))
+    );
+  }
+
+  #[test]
+  fn keeps_fixed_point_fractions() {
+    let half = Q16::from_f32(0.5f32);
+    assert_eq!(Q16::ONE, half.plus(half));
+    assert_eq!(Q16::from_int(3), Q16::from_f32(1.5f32).times(2));
+    assert_eq!(0.75f32, Q16::from_f32(1.5f32).divided_by(2).to_f32());
+  }
+}
@@ -0,0 +1,524 @@
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::error::DtwError;
+use crate::loss_fns::Loss;
+use crate::path::*;
+use crate::phase_weight::PhaseWeight;
+use crate::step_pattern::{Step, StepPattern};
+use crate::storage::Storage;
+use crate::window::{Row, Window};
+
+/// How many times the path's average loss a single point may cost before
+/// `widen_where_unreliable` counts it as a jump.
+const JUMP: u32 = 3;
+
+/// One cell of the grid: the loss between its two samples, and the best
+/// step out of it along with the weighted loss of all of the steps after it.
+/// Only the cells inside of the window get stored, so storage for far fewer
+/// cells than the full grid is enough for a guided solve.
+#[derive(Clone, Copy, Debug)]
+pub struct MapCell<C = f32> {
+  loss: C,
+  path: PathPoint<C>,
+}
+impl<C: Cost> MapCell<C> {
+  pub const EMPTY: MapCell<C> = MapCell {
+    loss: C::ZERO,
+    path: PathPoint {
+      loss: C::INFINITY,
+      to_parent: Move::Stop,
+    },
+  };
+}
+
+/// The cells of a single solve, along with the window of cells to evaluate.
+/// Only the cells inside of the current window are ever calculated or
+/// stored, packed row by row into `Cells`, so the same map can be reused to
+/// solve every level of a downsample pyramid and a guided solve only needs
+/// memory for the corridor around its path.
+///
+/// The storage may be fixed-size, in which case it has to be large enough
+/// for the biggest window it's used to solve, or `Vec`s that grow to fit.
+/// Losses are added up in `C`.
+pub struct CostMap<C: Cost, Cells: Storage<MapCell<C>>, Rows: Storage<Row>> {
+  cells: Cells,
+  window: Window<Rows>,
+  y_size: usize,
+  x_size: usize,
+  open_ends: bool,
+  downsample_factor: usize,
+  constraint: Option<GlobalConstraint>,
+  step_pattern: StepPattern,
+  phase_weight: Option<PhaseWeight>,
+  cost: core::marker::PhantomData<C>,
+}
+impl<C: Cost, Cells: Storage<MapCell<C>>, Rows: Storage<Row>> CostMap<C, Cells, Rows> {
+  pub const fn new(cells: Cells, rows: Rows) -> CostMap<C, Cells, Rows> {
+    CostMap {
+      cells,
+      window: Window::new(rows),
+      y_size: 0,
+      x_size: 0,
+      open_ends: false,
+      downsample_factor: 2,
+      constraint: None,
+      // The default pattern, since `Default` can't be called in a const fn
+      step_pattern: StepPattern::Symmetric2,
+      phase_weight: None,
+      cost: core::marker::PhantomData,
+    }
+  }
+
+  pub fn use_step_pattern(&mut self, step_pattern: StepPattern) {
+    self.step_pattern = step_pattern;
+  }
+
+  /// Sets how many times smaller each downsample is than the level above
+  /// it, for projecting its path onto the next level. Defaults to 2.
+  pub fn use_downsample_factor(&mut self, factor: usize) {
+    self.downsample_factor = factor;
+  }
+
+  /// Weighs the loss of every cell by how far apart its samples are (WDTW),
+  /// or stops weighing them with `None`. A pyramid solve sets the weight
+  /// for each level, scaled down to the size of that level.
+  pub fn use_phase_weight(&mut self, phase_weight: Option<PhaseWeight>) {
+    self.phase_weight = phase_weight;
+  }
+
+  /// Chooses the cells to evaluate on a `y_size` by `x_size` grid, given
+  /// the path found on a downsample of it (which starts from `ds_start_x` on
+  /// its first row). The cells within `radius` of the projected path get
+  /// evaluated, as long as they're inside of `constraint`. Without a
+  /// downsample path, every cell inside of the constraint is evaluated.
+  pub fn guide(
+    &mut self,
+    downsample_path: Option<&[PathPoint<C>]>,
+    ds_start_x: usize,
+    radius: usize,
+    constraint: Option<GlobalConstraint>,
+    y_size: usize,
+    x_size: usize,
+  ) -> Result<(), DtwError> {
+    if y_size == 0 || x_size == 0 {
+      return Err(DtwError::SignalTooShort);
+    }
+    self.constraint = constraint;
+
+    match downsample_path {
+      Some(dp) => {
+        self
+          .window
+          .project(dp, ds_start_x, self.downsample_factor, y_size, x_size)?;
+        self.window.expand(radius, y_size, x_size);
+      }
+      None => self.window.full(y_size, x_size),
+    };
+
+    // If the downsample path strayed too close to the edge of the
+    // constraint to leave a usable window inside it, fall back to solving
+    // the whole constrained area.
+    if let Some(constraint) = constraint {
+      let bounds = |y| constraint.row(y, y_size, x_size);
+      if !self.window.intersect(y_size, bounds) {
+        self.window.fill(y_size, bounds);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Whether the cell `(y, x)` is inside of the window, and so was
+  /// evaluated by the last solve.
+  #[inline]
+  pub fn in_window(&self, y: usize, x: usize) -> bool {
+    self.window.contains(y, x)
+  }
+
+  /// Widens the window wherever `path`, which starts from `start_x` on the
+  /// first row, looks unreliable: where it runs along an edge of the
+  /// window, or where a single point costs several times the path's average
+  /// loss. A path pressed up against the edge of its corridor was most
+  /// likely kept from a better route outside of it, and a jump in the loss
+  /// most likely marks a feature that the coarser levels smoothed away. The
+  /// window gets `margin` more cells in every direction around each such
+  /// point. Steps that the grid or the global constraint rule out don't
+  /// count as cut off. Returns `false` if there was nothing to widen.
+  ///
+  /// Every point is judged against the window the path was found in: the
+  /// widening is only marked along the way, and applied once the whole path
+  /// has been checked.
+  pub fn widen_where_unreliable(
+    &mut self,
+    path: &[PathPoint<C>],
+    start_x: usize,
+    margin: usize,
+  ) -> bool {
+    let (y_size, x_size, constraint) = (self.y_size, self.x_size, self.constraint);
+    let bounds = |y| match constraint {
+      Some(constraint) => constraint.row(y, y_size, x_size),
+      None => (0, x_size - 1),
+    };
+    let cut_off = |window: &Window<Rows>, y: usize, x: usize| {
+      if y >= y_size {
+        return false;
+      }
+      let (min_bound, max_bound) = bounds(y);
+      x >= min_bound && x <= max_bound && !window.contains(y, x)
+    };
+
+    let jump = match path.first() {
+      Some(first) => first.loss.divided_by(path.len() as u32).times(JUMP),
+      None => C::INFINITY,
+    };
+
+    let (mut y, mut x) = (0, start_x);
+    for point in path.iter() {
+      let unreliable = self.cell(y, x).loss > jump
+        || self.step_pattern.steps().iter().any(|step| {
+          let (dy, dx) = step.to_parent.offset();
+          cut_off(&self.window, y + dy, x + dx)
+            || (y >= dy && x >= dx && cut_off(&self.window, y - dy, x - dx))
+        });
+
+      if unreliable {
+        let rows = (
+          y.saturating_sub(margin),
+          core::cmp::min(y + margin, y_size - 1),
+        );
+        for near_y in rows.0..=rows.1 {
+          let (min_bound, max_bound) = bounds(near_y);
+          let span = (
+            core::cmp::max(x.saturating_sub(margin), min_bound),
+            core::cmp::min(x + margin, max_bound),
+          );
+          if span.0 <= span.1 {
+            self.window.mark((near_y, near_y), span);
+          }
+        }
+      }
+
+      if point.to_parent == Move::Stop {
+        break;
+      }
+      let (dy, dx) = point.to_parent.offset();
+      y += dy;
+      x += dx;
+    }
+
+    self.window.cover_marked(y_size)
+  }
+
+  /// Finds the best path between two signals through the cells of the
+  /// window.
+  #[inline]
+  pub fn solve<SampleType, P: PathBuffer<C>>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+  ) -> Result<P, DtwError> {
+    self.resize(sig_y.len(), sig_x.len())?;
+    self.open_ends = false;
+
+    self.map_cells(sig_y, sig_x, loss_fn, None);
+    Ok(self.get_best_path(0)?.0)
+  }
+
+  /// Traces the best path out of the cells of the last solve, without
+  /// solving again. The last solve has to have evaluated the whole window.
+  #[inline]
+  pub fn trace<P: PathBuffer<C>>(&self) -> Result<P, DtwError> {
+    Ok(self.get_best_path(0)?.0)
+  }
+
+  /// Like `solve`, but also works out the distance between the signals.
+  #[inline]
+  pub fn align<SampleType, P: PathBuffer<C>>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+  ) -> Result<DtwResult<P, C>, DtwError> {
+    let path: P = self.solve(sig_y, sig_x, loss_fn)?;
+    let distance = self.path_loss(0, 0);
+    let normalized_distance = match distance == C::INFINITY {
+      true => C::INFINITY,
+      false => distance.divided_by(self.step_pattern.path_weight(path.points())),
+    };
+
+    Ok(DtwResult {
+      distance,
+      normalized_distance,
+      path,
+    })
+  }
+
+  /// The loss of the best path between two signals through the cells of the
+  /// window, without tracing the path itself.
+  #[inline]
+  pub fn distance<SampleType>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+  ) -> Result<C, DtwError> {
+    self.resize(sig_y.len(), sig_x.len())?;
+    self.open_ends = false;
+
+    self.map_cells(sig_y, sig_x, loss_fn, None);
+    Ok(self.path_loss(0, 0))
+  }
+
+  /// Like `distance`, but gives up as soon as it's clear that the distance
+  /// is greater than `best_so_far`.
+  #[inline]
+  pub fn distance_within<SampleType>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+    best_so_far: C,
+  ) -> Result<DistanceOutcome<C>, DtwError> {
+    self.resize(sig_y.len(), sig_x.len())?;
+    self.open_ends = false;
+
+    if !self.map_cells(sig_y, sig_x, loss_fn, Some(best_so_far)) {
+      return Ok(DistanceOutcome::Abandoned);
+    }
+
+    Ok(match self.path_loss(0, 0) {
+      distance if distance <= best_so_far => DistanceOutcome::Distance(distance),
+      _ => DistanceOutcome::Abandoned,
+    })
+  }
+
+  /// Finds where `sig_y` best matches inside of `sig_x`, through the cells
+  /// of the window. The path may start anywhere on the first row and end
+  /// anywhere on the last.
+  #[inline]
+  pub fn solve_subsequence<SampleType, P: PathBuffer<C>>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+  ) -> Result<Subsequence<P, C>, DtwError> {
+    self.resize(sig_y.len(), sig_x.len())?;
+    self.open_ends = true;
+
+    self.map_cells(sig_y, sig_x, loss_fn, None);
+
+    let (min_x, max_x) = self.window.row(0);
+    let mut start = min_x;
+    for x in min_x..=max_x {
+      if self.path_loss(0, x) < self.path_loss(0, start) {
+        start = x;
+      }
+    }
+
+    let (path, end) = self.get_best_path(start)?;
+    Ok(Subsequence {
+      start,
+      end,
+      loss: self.path_loss(0, start),
+      path,
+    })
+  }
+
+  #[inline]
+  fn resize(&mut self, y_size: usize, x_size: usize) -> Result<(), DtwError> {
+    self.y_size = y_size;
+    self.x_size = x_size;
+
+    let len = self.window.pack(y_size);
+    self.cells.make_room(len, MapCell::EMPTY);
+    match len <= self.cells.items().len() {
+      true => Ok(()),
+      false => Err(DtwError::CellCapacityExceeded {
+        needed: len,
+        capacity: self.cells.items().len(),
+      }),
+    }
+  }
+
+  #[inline]
+  fn cell(&self, y: usize, x: usize) -> &MapCell<C> {
+    &self.cells.items()[self.window.index(y, x)]
+  }
+
+  #[inline]
+  fn cell_mut(&mut self, y: usize, x: usize) -> &mut MapCell<C> {
+    let index = self.window.index(y, x);
+    &mut self.cells.items_mut()[index]
+  }
+
+  #[inline]
+  fn calc_loss_cell<SampleType>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+    y: usize,
+    x: usize,
+  ) {
+    let loss = loss_fn.loss(&sig_y[y], &sig_x[x]);
+    self.cell_mut(y, x).loss = match self.phase_weight {
+      Some(phase_weight) => loss.scaled(phase_weight.weight(y, x, self.y_size, self.x_size)),
+      None => loss,
+    };
+  }
+
+  /// Works out the loss and best step of every cell in the window. Both are
+  /// calculated a row at a time, since a row's steps only lead onto the rows
+  /// above it.
+  ///
+  /// Given a `best_so_far` loss, gives up and returns `false` as soon as no
+  /// path through the rows done so far can beat it. Losses are never
+  /// negative and every step weighs a cell by at least 1, so a path is
+  /// always at least as lossy as any of its cells' `path_loss`.
+  #[inline]
+  fn map_cells<SampleType>(
+    &mut self,
+    sig_y: &[SampleType],
+    sig_x: &[SampleType],
+    loss_fn: &impl Loss<SampleType, C>,
+    best_so_far: Option<C>,
+  ) -> bool {
+    // Longer steps can skip over rows, but every path lands somewhere in any
+    // run of rows as tall as its tallest step.
+    let landing_rows = self.step_pattern.max_rows();
+
+    // We go right-to-left, top-to-bottom through the window so that the
+    // cells above and to the right of each cell are always ready.
+    for my in 0..self.y_size {
+      let y = self.y_size - my - 1;
+      let (min_x, max_x) = self.window.row(y);
+      for x in min_x..=max_x {
+        self.calc_loss_cell(sig_y, sig_x, loss_fn, y, x);
+      }
+      for x in (min_x..=max_x).rev() {
+        self.calc_path_cell(y, x);
+      }
+
+      if let Some(best_so_far) = best_so_far {
+        let last_y = core::cmp::min(y + landing_rows, self.y_size) - 1;
+        if (y..=last_y).all(|y| self.row_min_loss(y) > best_so_far) {
+          return false;
+        }
+      }
+    }
+
+    true
+  }
+
+  /// The lowest `path_loss` of any cell in row `y` of the window.
+  #[inline]
+  fn row_min_loss(&self, y: usize) -> C {
+    let (min_x, max_x) = self.window.row(y);
+    (min_x..=max_x)
+      .map(|x| self.path_loss(y, x))
+      .fold(C::INFINITY, |min, loss| match loss < min {
+        true => loss,
+        false => min,
+      })
+  }
+
+  #[inline]
+  fn calc_path_cell(&mut self, y: usize, x: usize) {
+    // With open ends, the path can stop anywhere on the last row
+    if y == self.y_size - 1 && (self.open_ends || x == self.x_size - 1) {
+      self.cell_mut(y, x).path = PathPoint {
+        loss: C::ZERO,
+        to_parent: Move::Stop,
+      };
+      return;
+    }
+
+    // If no step leads anywhere, the cell is a dead end
+    let mut best = PathPoint {
+      loss: C::INFINITY,
+      to_parent: Move::Stop,
+    };
+
+    for step in self.step_pattern.steps() {
+      let loss = self.step_loss(y, x, step);
+      if loss < best.loss {
+        best = PathPoint {
+          loss,
+          to_parent: step.to_parent,
+        };
+      }
+    }
+
+    self.cell_mut(y, x).path = best;
+  }
+
+  /// The loss of taking `step` out of a cell, plus the loss of the rest of
+  /// the path from where it lands. Steps that leave the window or lead to a
+  /// dead end are "infinitely" lossy.
+  #[inline]
+  fn step_loss(&self, y: usize, x: usize, step: &Step) -> C {
+    let (dy, dx) = step.to_parent.offset();
+    if !self.is_open(y + dy, x + dx) {
+      return C::INFINITY;
+    }
+
+    let mut loss = self.cell(y + dy, x + dx).path.loss;
+    if loss == C::INFINITY {
+      return C::INFINITY;
+    }
+
+    for &(wy, wx, weight) in step.weights {
+      if !self.is_open(y + wy, x + wx) {
+        return C::INFINITY;
+      }
+      loss = loss.plus(self.cell(y + wy, x + wx).loss.times(weight));
+    }
+
+    loss
+  }
+
+  /// Whether a cell is on the grid and inside of the window.
+  #[inline]
+  fn is_open(&self, y: usize, x: usize) -> bool {
+    y < self.y_size && x < self.x_size && self.window.contains(y, x)
+  }
+
+  /// The loss of the best path from a cell to the end, including the
+  /// cell's own loss.
+  #[inline]
+  fn path_loss(&self, y: usize, x: usize) -> C {
+    let cell = self.cell(y, x);
+    match cell.path.loss == C::INFINITY {
+      true => C::INFINITY,
+      false => cell.path.loss.plus(cell.loss),
+    }
+  }
+
+  /// Follows the path map from `start_x` on the first row until it stops.
+  /// Returns the path along with the column it stopped in.
+  #[inline]
+  fn get_best_path<P: PathBuffer<C>>(&self, start_x: usize) -> Result<(P, usize), DtwError> {
+    let mut y = 0;
+    let mut x = start_x;
+    let mut path = P::empty_path();
+
+    loop {
+      let to_parent = self.cell(y, x).path.to_parent;
+      path.push_point(PathPoint {
+        loss: self.path_loss(y, x),
+        to_parent,
+      })?;
+
+      let (dy, dx) = to_parent.offset();
+      y += dy;
+      x += dx;
+
+      if self.cell(y, x).path.to_parent == Move::Stop {
+        break;
+      }
+    }
+
+    Ok((path, x))
+  }
+}
@@ -0,0 +1,91 @@
+//! Derivative DTW (DDTW): aligning signals by their local slopes rather than
+//! their values, so that a path matches rising sections to rising sections
+//! and peaks to peaks even when the signals are offset from each other.
+
+/// A sample type whose derivative can be estimated from its neighbours.
+pub trait Derivative: Copy {
+  /// Keogh and Pazzani's estimate of the derivative at `sample`: the average
+  /// of the slope from `prev` to `sample` and the slope from `prev` to
+  /// `next`, which holds up better than a plain difference against noise.
+  fn estimate(prev: &Self, sample: &Self, next: &Self) -> Self;
+}
+
+impl Derivative for f32 {
+  #[inline]
+  fn estimate(prev: &f32, sample: &f32, next: &f32) -> f32 {
+    ((*sample - *prev) + (*next - *prev) / 2f32) / 2f32
+  }
+}
+
+impl Derivative for f64 {
+  #[inline]
+  fn estimate(prev: &f64, sample: &f64, next: &f64) -> f64 {
+    ((*sample - *prev) + (*next - *prev) / 2f64) / 2f64
+  }
+}
+
+/// Estimates each dimension on its own.
+impl<const N: usize> Derivative for [f32; N] {
+  #[inline]
+  fn estimate(prev: &[f32; N], sample: &[f32; N], next: &[f32; N]) -> [f32; N] {
+    let mut derivative = *sample;
+    for i in 0..N {
+      derivative[i] = f32::estimate(&prev[i], &sample[i], &next[i]);
+    }
+    derivative
+  }
+}
+
+/// Something that writes the derivative of a signal into a buffer, like
+/// `derive`. Lets the solvers hold on to a derivative without requiring
+/// every sample type to have one.
+pub(crate) type DeriveFn<SampleType> = fn(&[SampleType], &mut [SampleType]);
+
+/// Writes the derivative of `signal` into the start of `derivative`, which
+/// has to be at least as long. The estimate needs a sample on either side,
+/// so the first and last samples take the derivative of their neighbours.
+/// Signals shorter than 3 samples stand in for the missing neighbours with
+/// the samples themselves.
+pub fn derive<SampleType: Derivative>(signal: &[SampleType], derivative: &mut [SampleType]) {
+  let len = signal.len();
+  if len < 3 {
+    for t in 0..len {
+      derivative[t] = SampleType::estimate(
+        &signal[t.saturating_sub(1)],
+        &signal[t],
+        &signal[core::cmp::min(t + 1, len - 1)],
+      );
+    }
+    return;
+  }
+
+  for t in 1..len - 1 {
+    derivative[t] = SampleType::estimate(&signal[t - 1], &signal[t], &signal[t + 1]);
+  }
+  derivative[0] = derivative[1];
+  derivative[len - 1] = derivative[len - 2];
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn estimates_keogh_pazzani_derivative() {
+    let signal = [0f32, 1f32, 3f32, 6f32, 7f32];
+    let mut derivative = [0f32; 5];
+    derive(&signal, &mut derivative);
+
+    assert_eq!([1.25f32, 1.25f32, 2.25f32, 2.5f32, 2.5f32], derivative);
+  }
+
+  #[test]
+  fn derives_each_dimension() {
+    let signal = [[0f32, 4f32], [1f32, 2f32], [3f32, 0f32]];
+    let mut derivative = [[0f32; 2]; 3];
+    derive(&signal, &mut derivative);
+
+    assert_eq!([[1.25f32, -2f32]; 3], derivative);
+  }
+}
@@ -1,4 +1,5 @@
-use crate::alloc::alloc;
+use crate::derivative::DeriveFn;
+use crate::downsample_fns::Downsampler;
 use crate::stack_vec::StackVec;
 
 #[derive(Debug)]
@@ -7,61 +8,181 @@ pub struct Downsample<SampleType, const SIGNAL_SIZE: usize> {
   pub len: usize,
 }
 impl<SampleType, const SIGNAL_SIZE: usize> Downsample<SampleType, SIGNAL_SIZE> {
+  /// Replaces the downsample with `signal` shrunk by `factor`, reusing its
+  /// array. Returns `false`, leaving it alone, if the signal is too short to
+  /// shrink.
   #[inline]
-  pub fn create_one<'a>(
-    signal: &[SampleType; SIGNAL_SIZE],
-    len: usize,
-    downsample_fn: fn(&SampleType, &SampleType) -> SampleType,
-  ) -> Option<Downsample<SampleType, SIGNAL_SIZE>> {
-    if len < 4 || len % 2 != 0 {
-      return None;
+  pub fn shrink_from(
+    &mut self,
+    signal: &[SampleType],
+    factor: usize,
+    downsample_fn: &impl Downsampler<SampleType>,
+  ) -> bool {
+    let ds_signal = &mut self.signal;
+    if !shrink(signal, factor, downsample_fn, |t, sample| {
+      ds_signal[t] = sample
+    }) {
+      return false;
     }
 
-    let mut ds_signal: [SampleType; SIGNAL_SIZE] = alloc(false);
+    self.len = signal.len() / factor;
+    true
+  }
 
-    for t in 0..(len / 2) {
-      ds_signal[t] = downsample_fn(&signal[t * 2], &signal[t * 2 + 1]);
-    }
+  /// Writes the derivative of the downsample into the back half of its
+  /// array with `derive_fn`. A downsample is at most half as long as the
+  /// array, so the back half is never used, and the downsample itself is
+  /// left alone to be shrunk again.
+  #[inline]
+  pub fn derive(&mut self, derive_fn: DeriveFn<SampleType>) {
+    let (signal, derivative) = self.signal.split_at_mut(self.len);
+    derive_fn(signal, derivative);
+  }
 
-    Some(Downsample {
-      signal: ds_signal,
-      len: len / 2,
-    })
+  /// The derivative written by `derive`.
+  #[inline]
+  pub fn derivative(&self) -> &[SampleType] {
+    &self.signal[self.len..self.len * 2]
   }
 
+  /// Shrinks a signal over and over, until it's too short to shrink again
+  /// or it's been shrunk `downsample_limit` times, into the arrays already
+  /// in `downsamples`. Whatever they held before gets replaced, so the same
+  /// pyramid can be rebuilt for every solve. The smallest downsample comes
+  /// last.
   #[inline]
-  pub fn create_all<const MAX_DOWNSAMPLES: usize>(
+  pub fn shrink_all<const MAX_DOWNSAMPLES: usize>(
+    downsamples: &mut StackVec<Downsample<SampleType, SIGNAL_SIZE>, MAX_DOWNSAMPLES>,
     signal: &[SampleType; SIGNAL_SIZE],
-    downsample_fn: fn(&SampleType, &SampleType) -> SampleType,
+    factor: usize,
+    downsample_fn: &impl Downsampler<SampleType>,
     downsample_limit: Option<usize>,
-  ) -> StackVec<Downsample<SampleType, SIGNAL_SIZE>, MAX_DOWNSAMPLES> {
-    let mut downsamples =
-      StackVec::<Downsample<SampleType, SIGNAL_SIZE>, MAX_DOWNSAMPLES>::empty(false);
-
+  ) {
     let ds_limit = match downsample_limit {
       Some(limit) => core::cmp::min(limit, MAX_DOWNSAMPLES),
       None => MAX_DOWNSAMPLES,
     };
 
-    for _ in 0..ds_limit {
-      let (sig, len) = match downsamples.len() {
-        0 => (signal, SIGNAL_SIZE),
-        _ => (
-          &downsamples[downsamples.len() - 1].signal,
-          downsamples[downsamples.len() - 1].len,
-        ),
+    downsamples.set_len(0);
+    for level in 0..ds_limit {
+      let (shrunk, unused) = downsamples.slots_mut().split_at_mut(level);
+      let sig = match shrunk.last() {
+        Some(ds) => &ds.signal[..ds.len],
+        None => &signal[..],
       };
 
-      match Downsample::create_one(sig, len, downsample_fn) {
-        Some(ds) => {
-          downsamples.push(ds);
-        }
-        None => {
-          break;
-        }
-      };
+      if !unused[0].shrink_from(sig, factor, downsample_fn) {
+        break;
+      }
+      downsamples.set_len(level + 1);
     }
+  }
+}
+
+/// Shrinks a signal by `factor` by combining each run of `factor`
+/// neighbouring samples with `downsample_fn` (which can look past the run),
+/// handing each new sample to `put` along with its index. A signal that
+/// doesn't divide evenly has a few samples left over, which get folded into
+/// the last bin rather than dropped. Returns `false` without downsampling if
+/// the signal is too short to shrink into at least 2 samples.
+#[inline]
+pub fn shrink<SampleType>(
+  signal: &[SampleType],
+  factor: usize,
+  downsample_fn: &impl Downsampler<SampleType>,
+  mut put: impl FnMut(usize, SampleType),
+) -> bool {
+  let len = signal.len();
+  let ds_len = len / factor;
+  if ds_len < 2 {
+    return false;
+  }
+
+  for t in 0..ds_len {
+    let end = match t == ds_len - 1 {
+      true => len,
+      false => (t + 1) * factor,
+    };
+    put(t, downsample_fn.downsample_bin(signal, t * factor..end));
+  }
+
+  true
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::downsample_fns;
+
+  fn shrink_one<const N: usize>(signal: &[f32], factor: usize) -> Downsample<f32, N> {
+    let mut ds = Downsample {
+      signal: [0f32; N],
+      len: 0,
+    };
+    assert!(ds.shrink_from(signal, factor, &downsample_fns::mean_u8));
+    ds
+  }
+
+  fn assert_lens<const N: usize>(downsamples: &StackVec<Downsample<f32, N>, 16>, lens: &[usize]) {
+    assert_eq!(lens.len(), downsamples.len());
+    for (ds, len) in downsamples.iter().zip(lens.iter()) {
+      assert_eq!(*len, ds.len);
+    }
+  }
+
+  #[test]
+  fn folds_leftover_sample_into_last_bin() {
+    let signal = [1f32, 3f32, 5f32, 7f32, 2f32];
+    let ds = shrink_one::<5>(&signal, 2);
+    assert_eq!(2, ds.len);
+    assert_eq!(2f32, ds.signal[0]);
+    assert_eq!(14f32 / 3f32, ds.signal[1]);
+  }
+
+  #[test]
+  fn keeps_halving_odd_lengths() {
+    let signal = [0f32; 999];
+    let mut downsamples = StackVec::empty(false);
+    Downsample::shrink_all(&mut downsamples, &signal, 2, &downsample_fns::mean_u8, None);
+    assert_lens(&downsamples, &[499, 249, 124, 62, 31, 15, 7, 3]);
+  }
+
+  #[test]
+  fn shrinks_by_any_factor() {
+    let signal = [1f32, 2f32, 3f32, 4f32, 5f32, 6f32, 7f32, 8f32, 9f32, 10f32];
+    let ds = shrink_one::<10>(&signal, 4);
+    assert_eq!(2, ds.len);
+    assert_eq!(2.5f32, ds.signal[0]);
+    assert_eq!(7.5f32, ds.signal[1]);
+
+    let signal = [0f32; 999];
+    let mut downsamples = StackVec::empty(false);
+    Downsample::shrink_all(&mut downsamples, &signal, 3, &downsample_fns::mean_u8, None);
+    assert_lens(&downsamples, &[333, 111, 37, 12, 4]);
+  }
+
+  #[test]
+  fn rebuilds_pyramid_in_place() {
+    let mut downsamples = StackVec::zeroed();
+    Downsample::shrink_all(
+      &mut downsamples,
+      &[0f32; 64],
+      2,
+      &downsample_fns::mean_u8,
+      None,
+    );
+    assert_lens(&downsamples, &[32, 16, 8, 4, 2]);
 
-    downsamples
+    let signal = [4f32; 64];
+    Downsample::shrink_all(
+      &mut downsamples,
+      &signal,
+      4,
+      &downsample_fns::mean_u8,
+      Some(2),
+    );
+    assert_lens(&downsamples, &[16, 4]);
+    assert_eq!(4f32, downsamples[1].signal[3]);
   }
 }
@@ -1,18 +1,350 @@
 use crate::alloc::alloc;
+use core::ops::Range;
 
+/// Combines a run of neighbouring samples into one, to shrink a signal.
+/// The run is as long as the downsample factor, or longer for the last bin
+/// of a signal that doesn't divide evenly.
+///
+/// Implemented for any closure or function that takes a slice of samples
+/// and returns a new one, including the ones in this module.
+pub trait Downsampler<SampleType> {
+  fn downsample(&self, samples: &[SampleType]) -> SampleType;
+
+  /// Combines the bin `signal[bin]`, with the rest of the signal around it
+  /// in view. Downsamplers that reach past the edges of their bin, like a
+  /// Gaussian low-pass, see their neighbours through this; the rest are
+  /// handed the bin alone.
+  #[inline]
+  fn downsample_bin(&self, signal: &[SampleType], bin: Range<usize>) -> SampleType {
+    self.downsample(&signal[bin])
+  }
+}
+impl<SampleType, F: Fn(&[SampleType]) -> SampleType> Downsampler<SampleType> for F {
+  #[inline]
+  fn downsample(&self, samples: &[SampleType]) -> SampleType {
+    self(samples)
+  }
+}
+
+/// The mean of the bin. It takes `f32` samples, whatever the name says.
 #[inline]
-pub fn mean_u8(s1: &f32, s2: &f32) -> f32 {
-  (*s1 + *s2) / 2f32
-  //*s1 / 2 + *s2 / 2
+pub fn mean_u8(samples: &[f32]) -> f32 {
+  samples.iter().sum::<f32>() / samples.len() as f32
 }
 
+/// The mean of each dimension of the bin.
 #[inline]
-pub fn mean<const N: usize>(s1: &[f32; N], s2: &[f32; N]) -> [f32; N] {
+pub fn mean<const N: usize>(samples: &[[f32; N]]) -> [f32; N] {
   let mut mean: [f32; N] = alloc(false);
 
   for i in 0..N {
-    mean[i] = mean_u8(&s1[i], &s2[i]);
+    mean[i] = samples.iter().map(|s| s[i]).sum::<f32>() / samples.len() as f32;
   }
 
   mean
 }
+
+/// The highest sample of the bin, which keeps peaks and edges from being
+/// averaged away.
+#[inline]
+pub fn max(samples: &[f32]) -> f32 {
+  samples.iter().fold(f32::MIN, |max, s| libm::fmaxf(max, *s))
+}
+
+/// The lowest sample of the bin.
+#[inline]
+pub fn min(samples: &[f32]) -> f32 {
+  samples.iter().fold(f32::MAX, |min, s| libm::fminf(min, *s))
+}
+
+/// The middle sample of the bin, or the mean of the two middle samples of a
+/// bin of even length. Unlike the mean, it ignores the odd spike.
+#[inline]
+pub fn median(samples: &[f32]) -> f32 {
+  median_by(samples.len(), |t| samples[t])
+}
+
+/// The first sample of the bin, dropping the rest (decimation). The
+/// cheapest of the lot, but detail finer than a bin aliases into the
+/// downsample.
+#[inline]
+pub fn first(samples: &[f32]) -> f32 {
+  samples[0]
+}
+
+/// Piecewise Aggregate Approximation, the name the time series literature
+/// gives to the mean of each segment of a signal. The same function as
+/// `mean_u8`.
+pub use self::mean_u8 as paa;
+
+/// The mean of the samples within `3 * sigma` of the middle of `bin` (or of
+/// the samples nearest the middle, if none are that close), weighted by a
+/// Gaussian of standard deviation `sigma` samples. The kernel reaches into
+/// the neighbouring bins, so it low-passes the signal as it decimates it and
+/// detail finer than a bin doesn't alias into the downsample. A `sigma` of
+/// about half the downsample factor works well, and it has to be greater
+/// than 0.
+#[inline]
+pub fn gaussian(signal: &[f32], bin: Range<usize>, sigma: f32) -> f32 {
+  gaussian_by(signal.len(), bin, sigma, |t| signal[t])
+}
+
+/// The highest value of each dimension of the bin.
+#[inline]
+pub fn max_n<const N: usize>(samples: &[[f32; N]]) -> [f32; N] {
+  each_dimension(|i| {
+    samples
+      .iter()
+      .fold(f32::MIN, |max, s| libm::fmaxf(max, s[i]))
+  })
+}
+
+/// The lowest value of each dimension of the bin.
+#[inline]
+pub fn min_n<const N: usize>(samples: &[[f32; N]]) -> [f32; N] {
+  each_dimension(|i| {
+    samples
+      .iter()
+      .fold(f32::MAX, |min, s| libm::fminf(min, s[i]))
+  })
+}
+
+/// The median of each dimension of the bin, taken on its own.
+#[inline]
+pub fn median_n<const N: usize>(samples: &[[f32; N]]) -> [f32; N] {
+  each_dimension(|i| median_by(samples.len(), |t| samples[t][i]))
+}
+
+/// The first sample of the bin (decimation).
+#[inline]
+pub fn first_n<const N: usize>(samples: &[[f32; N]]) -> [f32; N] {
+  samples[0]
+}
+
+/// Piecewise Aggregate Approximation of each dimension. The same function
+/// as `mean`.
+pub use self::mean as paa_n;
+
+/// The Gaussian-weighted mean of each dimension around the bin.
+#[inline]
+pub fn gaussian_n<const N: usize>(signal: &[[f32; N]], bin: Range<usize>, sigma: f32) -> [f32; N] {
+  each_dimension(|i| gaussian_by(signal.len(), bin.clone(), sigma, |t| signal[t][i]))
+}
+
+/// The built-in ways of combining a bin of samples, picked at runtime. It
+/// downsamples both `f32` and `[f32; N]` samples, treating each dimension of
+/// an array on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownsampleStrategy {
+  Mean,
+  Max,
+  Min,
+  Median,
+  /// Decimation, keeping the first sample of each bin.
+  First,
+  /// Piecewise Aggregate Approximation, which is another name for `Mean`.
+  Paa,
+  /// A Gaussian low-pass of standard deviation `sigma` samples before
+  /// decimating. Its kernel reaches into the neighbouring bins.
+  Gaussian {
+    sigma: f32,
+  },
+}
+
+impl Downsampler<f32> for DownsampleStrategy {
+  #[inline]
+  fn downsample(&self, samples: &[f32]) -> f32 {
+    match *self {
+      DownsampleStrategy::Mean | DownsampleStrategy::Paa => mean_u8(samples),
+      DownsampleStrategy::Max => max(samples),
+      DownsampleStrategy::Min => min(samples),
+      DownsampleStrategy::Median => median(samples),
+      DownsampleStrategy::First => first(samples),
+      DownsampleStrategy::Gaussian { sigma } => gaussian(samples, 0..samples.len(), sigma),
+    }
+  }
+
+  #[inline]
+  fn downsample_bin(&self, signal: &[f32], bin: Range<usize>) -> f32 {
+    match *self {
+      DownsampleStrategy::Gaussian { sigma } => gaussian(signal, bin, sigma),
+      _ => self.downsample(&signal[bin]),
+    }
+  }
+}
+
+impl<const N: usize> Downsampler<[f32; N]> for DownsampleStrategy {
+  #[inline]
+  fn downsample(&self, samples: &[[f32; N]]) -> [f32; N] {
+    match *self {
+      DownsampleStrategy::Mean | DownsampleStrategy::Paa => mean(samples),
+      DownsampleStrategy::Max => max_n(samples),
+      DownsampleStrategy::Min => min_n(samples),
+      DownsampleStrategy::Median => median_n(samples),
+      DownsampleStrategy::First => first_n(samples),
+      DownsampleStrategy::Gaussian { sigma } => gaussian_n(samples, 0..samples.len(), sigma),
+    }
+  }
+
+  #[inline]
+  fn downsample_bin(&self, signal: &[[f32; N]], bin: Range<usize>) -> [f32; N] {
+    match *self {
+      DownsampleStrategy::Gaussian { sigma } => gaussian_n(signal, bin, sigma),
+      _ => self.downsample(&signal[bin]),
+    }
+  }
+}
+
+#[inline]
+fn each_dimension<const N: usize>(value: impl Fn(usize) -> f32) -> [f32; N] {
+  let mut sample: [f32; N] = alloc(false);
+  for (i, s) in sample.iter_mut().enumerate() {
+    *s = value(i);
+  }
+  sample
+}
+
+/// The median of the `len` values given by `value`. Bins are short, so
+/// rather than sorting a copy, each order statistic is found by counting
+/// how many values fall below each candidate.
+#[inline]
+fn median_by(len: usize, value: impl Fn(usize) -> f32) -> f32 {
+  let nth = |n: usize| {
+    (0..len)
+      .map(&value)
+      .find(|v| {
+        let below = (0..len).filter(|&t| value(t) < *v).count();
+        let at_most = (0..len).filter(|&t| value(t) <= *v).count();
+        below <= n && n < at_most
+      })
+      .unwrap_or_else(|| value(0))
+  };
+
+  match len % 2 {
+    1 => nth(len / 2),
+    _ => (nth(len / 2 - 1) + nth(len / 2)) / 2f32,
+  }
+}
+
+#[inline]
+fn gaussian_by(len: usize, bin: Range<usize>, sigma: f32, value: impl Fn(usize) -> f32) -> f32 {
+  let middle = (bin.start + bin.end - 1) as f32 / 2f32;
+  let first = libm::ceilf(libm::fmaxf(middle - 3f32 * sigma, 0f32)) as usize;
+  let last = libm::floorf(libm::fminf(middle + 3f32 * sigma, (len - 1) as f32)) as usize;
+
+  // A kernel narrower than half a sample can miss every sample, so the ones
+  // nearest the middle are always in. Weighing them against the nearest one
+  // rather than the middle keeps it from rounding every weight down to 0.
+  let first = core::cmp::min(first, libm::floorf(middle) as usize);
+  let last = core::cmp::max(last, libm::ceilf(middle) as usize);
+  let nearest = middle - libm::floorf(middle);
+
+  let (mut sum, mut total_weight) = (0f32, 0f32);
+  for t in first..=last {
+    let distance = (t as f32 - middle) / sigma;
+    let offset = nearest / sigma;
+    let weight = libm::expf((offset * offset - distance * distance) / 2f32);
+    sum += weight * value(t);
+    total_weight += weight;
+  }
+  sum / total_weight
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::downsample::shrink;
+  use crate::{loss_fns, DtwSolver, ExactDtwSolver};
+
+  #[test]
+  fn combines_bins_by_each_strategy() {
+    let bin = [2f32, 9f32, 1f32, 4f32];
+    let strategy = |s: DownsampleStrategy| Downsampler::<f32>::downsample(&s, &bin);
+
+    assert_eq!(4f32, strategy(DownsampleStrategy::Mean));
+    assert_eq!(9f32, strategy(DownsampleStrategy::Max));
+    assert_eq!(1f32, strategy(DownsampleStrategy::Min));
+    assert_eq!(3f32, strategy(DownsampleStrategy::Median));
+    assert_eq!(2f32, strategy(DownsampleStrategy::First));
+    assert_eq!(4f32, strategy(DownsampleStrategy::Paa));
+    assert_eq!(4f32, median(&[4f32, 4f32, 9f32]));
+
+    // A narrow Gaussian leans on the middle two samples, a wide one tends
+    // towards the mean
+    let narrow = strategy(DownsampleStrategy::Gaussian { sigma: 0.5f32 });
+    let wide = strategy(DownsampleStrategy::Gaussian { sigma: 100f32 });
+    assert!(libm::fabsf(narrow - 5f32) < 0.5f32);
+    assert!(libm::fabsf(wide - 4f32) < 0.01f32);
+
+    // Even a kernel too narrow to reach a whole sample averages the ones
+    // nearest the middle
+    for sigma in [0.1f32, 0.001f32].iter() {
+      let pair = Downsampler::<f32>::downsample(
+        &DownsampleStrategy::Gaussian { sigma: *sigma },
+        &[1f32, 2f32],
+      );
+      assert!(pair.is_finite());
+      assert_eq!(1.5f32, pair);
+    }
+  }
+
+  #[test]
+  fn combines_each_dimension_on_its_own() {
+    let bin = [[2f32, 0f32], [9f32, -1f32], [1f32, 5f32]];
+    let strategy = |s: DownsampleStrategy| Downsampler::<[f32; 2]>::downsample(&s, &bin);
+
+    assert_eq!([9f32, 5f32], strategy(DownsampleStrategy::Max));
+    assert_eq!([1f32, -1f32], strategy(DownsampleStrategy::Min));
+    assert_eq!([2f32, 0f32], strategy(DownsampleStrategy::Median));
+    assert_eq!([2f32, 0f32], strategy(DownsampleStrategy::First));
+    assert_eq!([4f32, 4f32 / 3f32], strategy(DownsampleStrategy::Paa));
+  }
+
+  #[test]
+  fn guides_solves_with_any_strategy() {
+    let mut sig_y = [0f32; 64];
+    let mut sig_x = [0f32; 64];
+    for t in 0..64 {
+      sig_y[t] = ((t * 7) % 13) as f32;
+      sig_x[t] = (((t + 2) * 7) % 13) as f32;
+    }
+    let exact = ExactDtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, loss_fns::dist).distance();
+
+    for strategy in [
+      DownsampleStrategy::Max,
+      DownsampleStrategy::Median,
+      DownsampleStrategy::First,
+      DownsampleStrategy::Gaussian { sigma: 1f32 },
+    ]
+    .iter()
+    {
+      let guided =
+        DtwSolver::<f32, 64, 64, 127, _, _, _, _>::new(&sig_y, &sig_x, *strategy, loss_fns::dist)
+          .downsample_factor(3)
+          .search_radius(1)
+          .distance();
+
+      assert!(exact <= guided);
+    }
+  }
+
+  #[test]
+  fn gaussian_reaches_into_neighbouring_bins() {
+    let signal = [0f32, 0f32, 0f32, 8f32, 0f32, 0f32, 0f32, 0f32];
+    let shrink_by = |strategy: DownsampleStrategy| {
+      let mut shrunk = [0f32; 4];
+      shrink(&signal, 2, &strategy, |t, sample| shrunk[t] = sample);
+      shrunk
+    };
+
+    // The mean keeps the spike to its own bin, where the low-pass spreads
+    // it over the bins on either side
+    let mean = shrink_by(DownsampleStrategy::Mean);
+    let gaussian = shrink_by(DownsampleStrategy::Gaussian { sigma: 1f32 });
+    assert_eq!([0f32, 4f32, 0f32, 0f32], mean);
+    assert!(gaussian[0] > 0f32 && gaussian[2] > 0f32);
+    assert!(gaussian[1] < mean[1]);
+    assert_eq!(0f32, gaussian[3]);
+  }
+}
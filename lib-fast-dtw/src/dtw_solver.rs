@@ -1,419 +1,863 @@
-use core::cmp;
-
 use crate::alloc::alloc;
-use crate::downsample::Downsample;
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::cost_map::MapCell;
+use crate::derivative::Derivative;
+use crate::downsample_fns::Downsampler;
+use crate::dtw_workspace::{DtwWorkspace, MAX_DOWNSAMPLES};
+use crate::error::{or_panic, DtwError};
+use crate::evaluation::Evaluation;
+use crate::loss_fns::Loss;
 use crate::path::*;
-
-const MAX_DOWNSAMPLES: usize = 16;
-const INFINITY: f32 = core::f32::MAX;
-
-pub struct DtwSolver<'a, SampleType, const SIGNAL_SIZE: usize, const MAX_PATH_LEN: usize> {
-  sig_y: &'a [SampleType; SIGNAL_SIZE],
-  sig_x: &'a [SampleType; SIGNAL_SIZE],
-  signal_size: usize,
-  downsample_fn: fn(&SampleType, &SampleType) -> SampleType,
-  loss_fn: fn(&SampleType, &SampleType) -> f32,
-  loss_map: [[f32; SIGNAL_SIZE]; SIGNAL_SIZE],
-  path_map: [[PathPoint; SIGNAL_SIZE]; SIGNAL_SIZE],
-  downsample_limit: Option<usize>,
+use crate::phase_weight::PhaseWeight;
+use crate::stack_vec::StackVec;
+use crate::step_pattern::StepPattern;
+use crate::storage::Storage;
+
+/// Approximates the best path between two signals by solving a pyramid of
+/// downsamples, from the smallest up, each level guided by the path found
+/// on the level below it.
+///
+/// The cells of the maps are kept in `Cells`, which defaults to room for
+/// the full grid. A guided solve only stores the cells in the corridor
+/// around its path, though, so `with_cells` can be given far less.
+///
+/// Losses are added up in `C`, which defaults to `f32`. Integer and
+/// fixed-point costs like `u32` and `Q16` solve without any floating point
+/// arithmetic, for targets without an FPU.
+///
+/// The loss and downsample functions default to plain function pointers.
+/// Anything that implements `Loss` and `Downsampler` works, closures with
+/// state included, once the remaining parameters are left to inference:
+/// `DtwSolver::<f32, 64, 64, 127, _, _, _, _>::new(..)`.
+///
+/// Each solver builds its own maps and downsamples. To solve many pairs of
+/// signals in a row without building them again, use a `DtwWorkspace`.
+pub struct DtwSolver<
+  'a,
+  SampleType,
+  const SIGNAL_Y_SIZE: usize,
+  const SIGNAL_X_SIZE: usize,
+  const MAX_PATH_LEN: usize,
+  C: Cost = f32,
+  Cells: Storage<MapCell<C>> = [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+  LossFn: Loss<SampleType, C> = fn(&SampleType, &SampleType) -> C,
+  DownsampleFn: Downsampler<SampleType> = fn(&[SampleType]) -> SampleType,
+> {
+  sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+  sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+  workspace: DtwWorkspace<
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    Cells,
+    LossFn,
+    DownsampleFn,
+  >,
 }
-impl<'a, SampleType, const SIGNAL_SIZE: usize, const MAX_PATH_LEN: usize>
-  DtwSolver<'a, SampleType, SIGNAL_SIZE, MAX_PATH_LEN>
+impl<
+    'a,
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    LossFn: Loss<SampleType, C>,
+    DownsampleFn: Downsampler<SampleType>,
+  >
+  DtwSolver<
+    'a,
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+    LossFn,
+    DownsampleFn,
+  >
 {
   pub fn new(
-    sig_y: &'a [SampleType; SIGNAL_SIZE],
-    sig_x: &'a [SampleType; SIGNAL_SIZE],
-    downsample_fn: fn(&SampleType, &SampleType) -> SampleType,
-    loss_fn: fn(&SampleType, &SampleType) -> f32,
-  ) -> DtwSolver<'a, SampleType, SIGNAL_SIZE, MAX_PATH_LEN> {
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    downsample_fn: DownsampleFn,
+    loss_fn: LossFn,
+  ) -> Self {
+    DtwSolver::with_cells(sig_y, sig_x, downsample_fn, loss_fn, alloc(false))
+  }
+}
+impl<
+    'a,
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    Cells: Storage<MapCell<C>>,
+    LossFn: Loss<SampleType, C>,
+    DownsampleFn: Downsampler<SampleType>,
+  >
+  DtwSolver<
+    'a,
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    Cells,
+    LossFn,
+    DownsampleFn,
+  >
+{
+  /// How many bytes the solver itself takes up, with its maps, downsamples
+  /// and derivatives.
+  pub const fn struct_size() -> usize {
+    core::mem::size_of::<Self>()
+  }
+
+  /// How many bytes of stack the solver and a solve take up together: the
+  /// solver itself, plus the pyramid levels and the paths a solve keeps
+  /// along the way. At most four paths are alive at once, during
+  /// `evaluate`: the exact path it compares against, the path of the level
+  /// below, the result, and the path traced to refine it. The compiler's
+  /// own temporaries aren't counted, so leave some headroom when sizing a
+  /// thread's stack with it.
+  pub const fn stack_footprint() -> usize {
+    use core::mem::size_of;
+
+    Self::struct_size()
+      + size_of::<StackVec<(&[SampleType], &[SampleType]), { MAX_DOWNSAMPLES + 1 }>>()
+      + 2 * size_of::<DtwResult<Path<MAX_PATH_LEN, C>, C>>()
+      + size_of::<Option<Path<MAX_PATH_LEN, C>>>()
+      + size_of::<Path<MAX_PATH_LEN, C>>()
+  }
+
+  /// Creates a solver that keeps the cells of its maps in `cells`, which
+  /// only has to hold the cells inside of the largest window the solver
+  /// evaluates. With a search radius of `r`, a downsample factor of `k`, no
+  /// global constraint and signals of at least `4 * k` samples, that's at
+  /// most `(2 * r + 2 * k - 1) * (SIGNAL_Y_SIZE + SIGNAL_X_SIZE)` cells, or
+  /// `(2 * r + 3) * (SIGNAL_Y_SIZE + SIGNAL_X_SIZE)` when halving. The
+  /// solve fails with `DtwError::CellCapacityExceeded` if a window doesn't
+  /// fit, which can happen if a level has to be solved in full because the
+  /// one below it couldn't be.
+  pub fn with_cells(
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    downsample_fn: DownsampleFn,
+    loss_fn: LossFn,
+    cells: Cells,
+  ) -> Self {
     DtwSolver {
       sig_y,
       sig_x,
-      signal_size: SIGNAL_SIZE,
-      downsample_fn,
-      loss_fn,
-      loss_map: alloc(false),
-      path_map: alloc(false),
-      downsample_limit: None,
+      workspace: DtwWorkspace::with_cells_uninit(downsample_fn, loss_fn, cells),
     }
   }
 
   pub fn limit_downsamples(&mut self, max_downsamples: usize) -> &mut Self {
-    self.downsample_limit = Some(max_downsamples);
+    self.workspace.limit_downsamples(max_downsamples);
     self
   }
 
-  fn use_signal_size(&mut self, size: usize) {
-    self.signal_size = size;
+  /// Shrinks the signals by `factor` at each level of the downsample
+  /// pyramid, rather than halving them. A bigger factor means fewer levels
+  /// to solve, but a coarser path to guide each one with, so it takes a
+  /// wider search radius to stay as accurate. Factors below 2 are treated
+  /// as 2.
+  pub fn downsample_factor(&mut self, factor: usize) -> &mut Self {
+    self.workspace.downsample_factor(factor);
+    self
+  }
+
+  /// Widens the corridor of cells evaluated around each projected downsample
+  /// path by `radius` cells on each side. A larger radius trades speed for a
+  /// better chance of finding the optimal path.
+  pub fn search_radius(&mut self, radius: usize) -> &mut Self {
+    self.workspace.search_radius(radius);
+    self
+  }
+
+  /// Re-solves the stretches of each level where the guided path looks
+  /// unreliable: where it runs along the edge of its corridor, or where the
+  /// loss of a single point jumps well above the path's average. Those
+  /// stretches are widened by `margin` cells and the level solved again,
+  /// doubling the margin each time, until nothing is left to widen or the
+  /// level has been widened 4 times. The rest of the grid keeps its narrow
+  /// corridor, so this recovers most of what a coarse level got wrong
+  /// without solving exactly. Each round solves the level again though, so
+  /// on signals that differ everywhere, a larger radius may cost less for
+  /// the same accuracy. The cells of the widened windows need room in the
+  /// cell storage. Doesn't apply to subsequence matches.
+  pub fn refine(&mut self, margin: usize) -> &mut Self {
+    self.workspace.refine(margin);
+    self
+  }
+
+  /// Restricts the path to the cells allowed by a global constraint, at
+  /// every level of the downsample pyramid. Combined with
+  /// `limit_downsamples(0)`, this solves exactly within the constraint
+  /// without evaluating any cell outside of it.
+  pub fn constrain(&mut self, constraint: GlobalConstraint) -> &mut Self {
+    self.workspace.constrain(constraint);
+    self
+  }
+
+  /// Penalizes phase shifts with a logistic weight on every cell's loss
+  /// (weighted DTW). Each level of the downsample pyramid is weighed with
+  /// the offsets of the original signals its cells stand in for.
+  pub fn phase_weight(&mut self, phase_weight: PhaseWeight) -> &mut Self {
+    self.workspace.phase_weight(phase_weight);
+    self
+  }
+
+  /// Chooses the moves the path may take and how they're weighted. Defaults
+  /// to `StepPattern::Symmetric2`.
+  pub fn step_pattern(&mut self, step_pattern: StepPattern) -> &mut Self {
+    self.workspace.step_pattern(step_pattern);
+    self
+  }
+
+  /// Compares the derivatives of the signals rather than the signals
+  /// themselves (derivative DTW). Each level of the downsample pyramid is
+  /// derived on its own, so the smaller levels compare the derivatives of
+  /// downsamples rather than downsamples of derivatives.
+  pub fn derivatives(&mut self) -> &mut Self
+  where
+    SampleType: Derivative,
+  {
+    self.workspace.derivatives();
+    self
   }
 
   #[inline]
-  fn guided_solve(&mut self, downsample_path: &Option<Path<MAX_PATH_LEN>>) -> Path<MAX_PATH_LEN> {
-    self.map_losses(&downsample_path);
-    self.map_paths(&downsample_path);
-    self.get_best_path()
+  pub fn solve(&mut self) -> Path<MAX_PATH_LEN, C> {
+    or_panic(self.try_solve())
   }
 
+  /// Like `solve`, but returns an error rather than panicking if the path
+  /// can't be found.
   #[inline]
-  pub fn solve(&mut self) -> Path<MAX_PATH_LEN> {
-    let downsamples_y = Downsample::create_all::<MAX_DOWNSAMPLES>(
-      self.sig_y,
-      self.downsample_fn,
-      self.downsample_limit,
-    );
-    let downsamples_x = Downsample::create_all::<MAX_DOWNSAMPLES>(
-      self.sig_x,
-      self.downsample_fn,
-      self.downsample_limit,
-    );
-    let mut last_downsample_path = None;
-
-    for mi in 0..downsamples_y.len() {
-      let i = downsamples_y.len() - mi - 1;
-      let mut solver = DtwSolver::<SampleType, SIGNAL_SIZE, MAX_PATH_LEN>::new(
-        &downsamples_y[i].signal,
-        &downsamples_x[i].signal,
-        self.downsample_fn,
-        self.loss_fn,
-      );
-      solver.use_signal_size(downsamples_y[i].len);
-      last_downsample_path = Some(solver.guided_solve(&last_downsample_path));
-    }
+  pub fn try_solve(&mut self) -> Result<Path<MAX_PATH_LEN, C>, DtwError> {
+    self.workspace.try_solve(self.sig_y, self.sig_x)
+  }
 
-    self.guided_solve(&last_downsample_path)
+  /// Like `solve`, but also returns the distance between the signals.
+  #[inline]
+  pub fn align(&mut self) -> DtwResult<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_align())
   }
 
+  /// Like `align`, but returns an error rather than panicking if the path
+  /// can't be found.
   #[inline]
-  pub fn map_losses(&mut self, downsample_path: &Option<Path<MAX_PATH_LEN>>) {
-    // If we're building a subsample map, then there are uninitialized
-    // values to the top and right of the top-right cell. We need to set
-    // them to infinity so they don't mess up our min calculations later.
-    if self.signal_size < SIGNAL_SIZE {
-      self.loss_map[self.signal_size][self.signal_size - 2] = INFINITY;
-      self.loss_map[self.signal_size][self.signal_size - 1] = INFINITY;
-      self.loss_map[self.signal_size][self.signal_size] = INFINITY;
-      self.loss_map[self.signal_size - 1][self.signal_size] = INFINITY;
-      self.loss_map[self.signal_size - 2][self.signal_size] = INFINITY;
-    }
+  pub fn try_align(&mut self) -> Result<DtwResult<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self.workspace.try_align(self.sig_y, self.sig_x)
+  }
 
-    match downsample_path {
-      Some(ref dp) => {
-        // We always have to calculate losses for the 4 cells near the
-        // origin
-        self.calc_loss_cell(0, 0); // Corner
-        self.calc_loss_cell(0, 1); // Top
-        self.calc_loss_cell(1, 0); // Right
-        self.calc_loss_cell(1, 1); // Top-right
-        self.set_top_right_bounds(1, 1);
-
-        // Now follow the downsample path through the map
-        // (from the beginning of the signals), only
-        // calculating loss values for adjacent cells.
-
-        // Coordinates of the current path step on the upsample
-        let mut x = 1;
-        let mut y = 1;
-        let last_move: Option<Move> = None;
-
-        for path_move in dp.iter() {
-          match path_move.to_parent {
-            Move::Vertical => {
-              // Going up
-              y += 2;
-
-              // Set 3 boundary cells to "infinity",
-              // unless we're by the left edge
-              if x > 1 {
-                self.loss_map[y][x - 2] = INFINITY;
-                self.loss_map[y - 1][x - 2] = INFINITY;
-
-                // only set this one if we didn't move
-                // right just before this, because if we did,
-                // then we'll overwrite a previously calculated
-                // cell.
-                match last_move {
-                  Some(Move::Horizontal) => {
-                    self.loss_map[y - 2][x - 2] = INFINITY;
-                  }
-                  _ => {}
-                };
-              }
-
-              // Set 4 candidate blocks
-              self.calc_loss_cell(y - 1, x - 1);
-              self.calc_loss_cell(y - 1, x);
-              self.calc_loss_cell(y, x - 1);
-              self.calc_loss_cell(y, x);
-            }
-            Move::Horizontal => {
-              // Going right
-              x += 2;
-
-              // Set 3 boundary cells to "infinity",
-              // unless we're by the bottom edge
-              if y > 1 {
-                self.loss_map[y - 2][x] = INFINITY;
-                self.loss_map[y - 2][x - 1] = INFINITY;
-
-                // only set this one if we didn't move
-                // up just before this, because if we did,
-                // then we'll overwrite a previously calculated
-                // cell.
-                match last_move {
-                  Some(Move::Vertical) => {
-                    self.loss_map[y - 2][x - 2] = INFINITY;
-                  }
-                  _ => {}
-                };
-              }
-
-              // Set 4 candidate blocks
-              self.calc_loss_cell(y - 1, x - 1);
-              self.calc_loss_cell(y - 1, x);
-              self.calc_loss_cell(y, x - 1);
-              self.calc_loss_cell(y, x);
-            }
-            Move::Diagonal => {
-              // Going up and right
-              y += 2;
-              x += 2;
-
-              // Set 4 boundary cells to "infinity"
-              self.loss_map[y][x - 2] = INFINITY;
-              self.loss_map[y - 1][x - 3] = INFINITY;
-              self.loss_map[y - 2][x] = INFINITY;
-              self.loss_map[y - 3][x - 1] = INFINITY;
-
-              // Set 6 candidate blocks
-              self.calc_loss_cell(y - 2, x - 1);
-              self.calc_loss_cell(y - 1, x - 2);
-              self.calc_loss_cell(y - 1, x - 1);
-              self.calc_loss_cell(y - 1, x);
-              self.calc_loss_cell(y, x - 1);
-              self.calc_loss_cell(y, x);
-            }
-            Move::Stop => panic!("Invalid move"), // This variant doesn't apply here
-          };
+  /// The distance between the signals along the path `solve` would find,
+  /// without tracing the path itself. Useful when only the distance matters,
+  /// like when classifying signals by their nearest neighbour.
+  #[inline]
+  pub fn distance(&mut self) -> C {
+    or_panic(self.try_distance())
+  }
 
-          self.set_top_right_bounds(y, x);
-        }
-      }
-      None => {
-        // If we weren't given a downsample path, we fill out the
-        // loss map completely.
-        for y in 0..self.signal_size {
-          for x in 0..self.signal_size {
-            self.calc_loss_cell(y, x);
-          }
-        }
-      }
-    }
+  /// Like `distance`, but returns an error rather than panicking if the
+  /// distance can't be found.
+  #[inline]
+  pub fn try_distance(&mut self) -> Result<C, DtwError> {
+    self.workspace.try_distance(self.sig_y, self.sig_x)
   }
 
+  /// Like `distance`, but gives up as soon as every path left on the last
+  /// level is lossier than `best_so_far`, which saves most of the work when
+  /// searching for a nearest neighbour. Losses must not be negative. With
+  /// `refine`, only a last level that makes it under `best_so_far` gets
+  /// refined.
   #[inline]
-  pub fn calc_loss_cell(&mut self, y: usize, x: usize) {
-    let loss = (self.loss_fn)(&self.sig_y[y], &self.sig_x[x]);
-    let left = match x == 0 {
-      true => INFINITY,
-      false => self.loss_map[y][x - 1],
-    };
-    let down = match y == 0 {
-      true => INFINITY,
-      false => self.loss_map[y - 1][x],
-    };
-    let down_left = match y == 0 || x == 0 {
-      true => INFINITY,
-      false => self.loss_map[y - 1][x - 1],
-    };
+  pub fn distance_within(&mut self, best_so_far: C) -> DistanceOutcome<C> {
+    or_panic(self.try_distance_within(best_so_far))
+  }
 
-    let mut min = libm::fminf(left, libm::fminf(down, down_left));
-    if min == INFINITY {
-      min = 0f32;
-    }
-    self.loss_map[y][x] = loss + min;
+  /// Like `distance_within`, but returns an error rather than panicking if
+  /// the distance can't be found.
+  #[inline]
+  pub fn try_distance_within(&mut self, best_so_far: C) -> Result<DistanceOutcome<C>, DtwError> {
+    self
+      .workspace
+      .try_distance_within(self.sig_y, self.sig_x, best_so_far)
   }
 
+  /// Solves the signals both guided and exactly, and reports how far the
+  /// guided solve landed from the optimal path, for judging whether the
+  /// search radius and the rest of the settings suit a kind of signal. It
+  /// pays for a full exact solve, so the cell storage needs room for every
+  /// cell of the grid, or every cell inside of the global constraint.
   #[inline]
-  fn set_top_right_bounds(&mut self, y: usize, x: usize) {
-    // Set 5 boundary cells to the top and right
-    if y < SIGNAL_SIZE - 1 {
-      self.loss_map[y + 1][x - 1] = INFINITY;
-      self.loss_map[y + 1][x] = INFINITY;
+  pub fn evaluate(&mut self) -> Evaluation<C> {
+    or_panic(self.try_evaluate())
+  }
+
+  /// Like `evaluate`, but returns an error rather than panicking if either
+  /// solve fails.
+  #[inline]
+  pub fn try_evaluate(&mut self) -> Result<Evaluation<C>, DtwError> {
+    self.workspace.try_evaluate(self.sig_y, self.sig_x)
+  }
+
+  /// Finds where `sig_y` best matches inside of the longer `sig_x`. The
+  /// match may start and end anywhere along `sig_x`, and each level of the
+  /// downsample pyramid only searches near the match found on the level
+  /// below it. Global constraints don't apply to subsequence matches, since
+  /// the path isn't expected to follow the diagonal.
+  #[inline]
+  pub fn solve_subsequence(&mut self) -> Subsequence<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_solve_subsequence())
+  }
+
+  /// Like `solve_subsequence`, but returns an error rather than panicking
+  /// if the match can't be found.
+  #[inline]
+  pub fn try_solve_subsequence(
+    &mut self,
+  ) -> Result<Subsequence<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self.workspace.try_solve_subsequence(self.sig_y, self.sig_x)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::test_signals::{astray, LONG_X, LONG_Y, SPIKE_X, SPIKE_Y};
+  use crate::{downsample_fns, loss_fns, DtwError, ExactDtwSolver, MapCell};
+
+  fn path_end<const N: usize>(path: &Path<N>) -> (usize, usize) {
+    let (mut y, mut x) = (0, 0);
+    for point in path.iter() {
+      let (dy, dx) = point.to_parent.offset();
+      y += dy;
+      x += dx;
     }
+    (y, x)
+  }
+
+  #[test]
+  fn solves_signals_of_different_lengths() {
+    let sig_y = [1f32, 3f32, 1f32, 5f32, 2f32, 2f32, 4f32, 1f32];
+    let sig_x = [
+      1f32, 1f32, 3f32, 3f32, 1f32, 1f32, 5f32, 5f32, 2f32, 2f32, 2f32, 2f32, 4f32, 4f32, 1f32,
+      1f32,
+    ];
+
+    let path =
+      DtwSolver::<f32, 8, 16, 23>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .solve();
+    assert_eq!((7, 15), path_end(&path));
+
+    let path =
+      DtwSolver::<f32, 16, 8, 23>::new(&sig_x, &sig_y, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .solve();
+    assert_eq!((15, 7), path_end(&path));
+  }
 
-    if y < SIGNAL_SIZE - 1 && x < SIGNAL_SIZE - 1 {
-      self.loss_map[y + 1][x + 1] = INFINITY;
+  #[test]
+  fn solves_odd_signal_lengths() {
+    let sig_y = [
+      0f32, 1f32, 2f32, 3f32, 2f32, 1f32, 0f32, 1f32, 2f32, 3f32, 2f32, 1f32, 0f32,
+    ];
+    let sig_x = [0f32, 2f32, 3f32, 1f32, 0f32, 2f32, 3f32, 1f32, 0f32];
+
+    let path =
+      DtwSolver::<f32, 13, 9, 21>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .solve();
+    assert_eq!((12, 8), path_end(&path));
+  }
+
+  #[test]
+  fn wide_search_radius_finds_exact_path() {
+    let (sig_y, sig_x) = (LONG_Y, LONG_X);
+
+    let exact =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .solve();
+    let guided =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(16)
+        .solve();
+
+    for (e, g) in exact.iter().zip(guided.iter()) {
+      assert_eq!(e.to_parent, g.to_parent);
+      assert_eq!(e.loss, g.loss);
     }
+  }
 
-    if x < SIGNAL_SIZE - 1 {
-      self.loss_map[y][x + 1] = INFINITY;
-      self.loss_map[y - 1][x + 1] = INFINITY;
+  #[test]
+  fn stays_within_sakoe_chiba_band() {
+    let (sig_y, sig_x) = (SPIKE_Y, SPIKE_X);
+
+    for limit in [0, 16].iter() {
+      let path =
+        DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+          .constrain(GlobalConstraint::SakoeChibaBand { width: 3 })
+          .limit_downsamples(*limit)
+          .solve();
+
+      let (mut y, mut x) = (0i32, 0i32);
+      for point in path.iter() {
+        let (dy, dx) = point.to_parent.offset();
+        y += dy as i32;
+        x += dx as i32;
+        assert!((y - x).abs() <= 3);
+      }
+      assert_eq!((15, 15), (y, x));
     }
   }
 
-  #[inline]
-  pub fn map_paths(&mut self, downsample_path: &Option<Path<MAX_PATH_LEN>>) -> Path<MAX_PATH_LEN> {
-    match downsample_path {
-      Some(dp) => {
-        // If we have a downsample path, then we only calculate possible paths through
-        // the cells adjacent to it.
-        let mut y = self.signal_size - 1;
-        let mut x = self.signal_size - 1;
-
-        // Initialize the corner "Stop" cell and its 3 adjacent cells
-        self.calc_path_cell(y, x);
-        self.calc_path_cell(y, x - 1);
-        self.calc_path_cell(y - 1, x);
-        self.calc_path_cell(y - 1, x - 1);
-
-        for path_move in dp.iter().rev() {
-          match path_move.to_parent {
-            Move::Vertical => {
-              y -= 2;
-              self.calc_path_cell(y, x);
-              self.calc_path_cell(y, x - 1);
-              self.calc_path_cell(y - 1, x);
-              self.calc_path_cell(y - 1, x - 1);
-            }
-            Move::Horizontal => {
-              x -= 2;
-              self.calc_path_cell(y, x);
-              self.calc_path_cell(y, x - 1);
-              self.calc_path_cell(y - 1, x);
-              self.calc_path_cell(y - 1, x - 1);
-            }
-            Move::Diagonal => {
-              y -= 2;
-              x -= 2;
-              self.calc_path_cell(y + 1, x);
-              self.calc_path_cell(y, x + 1);
-              self.calc_path_cell(y, x);
-              self.calc_path_cell(y, x - 1);
-              self.calc_path_cell(y - 1, x);
-              self.calc_path_cell(y - 1, x - 1);
-            }
-            Move::Stop => {
-              break;
-            }
-          }
-        }
+  #[test]
+  fn stays_within_itakura_parallelogram() {
+    let (sig_y, sig_x) = (SPIKE_Y, SPIKE_X);
+
+    for limit in [0, 16].iter() {
+      let path =
+        DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+          .constrain(GlobalConstraint::Itakura { max_slope: 2f32 })
+          .limit_downsamples(*limit)
+          .solve();
+
+      let (mut y, mut x) = (0f32, 0f32);
+      for point in path.iter() {
+        let (dy, dx) = point.to_parent.offset();
+        y += dy as f32;
+        x += dx as f32;
+        assert!(x <= 2f32 * y && y <= 2f32 * x);
+        assert!(15f32 - x <= 2f32 * (15f32 - y) && 15f32 - y <= 2f32 * (15f32 - x));
       }
-      None => {
-        // If there's no downsample path, then we're calculating paths
-        // for every cell in the grid. We go right-to-left, top-to-bottom.
-        for my in 0..self.signal_size {
-          let y = self.signal_size - my - 1;
-          for mx in 0..self.signal_size {
-            let x = self.signal_size - mx - 1;
-            self.calc_path_cell(y, x);
+      assert_eq!((15f32, 15f32), (y, x));
+    }
+  }
+
+  #[test]
+  fn fits_itakura_to_rectangular_grids() {
+    macro_rules! solve_at_slopes {
+      ($y_size:literal, $x_size:literal, $path_len:literal) => {
+        let sig_y: [f32; $y_size] = core::array::from_fn(|t| (t * 5 % 7) as f32);
+        let sig_x: [f32; $x_size] = core::array::from_fn(|t| (t * 3 % 7) as f32);
+        for max_slope in [0.5f32, 1f32].iter() {
+          let itakura = GlobalConstraint::Itakura {
+            max_slope: *max_slope,
+          };
+          let exact =
+            ExactDtwSolver::<f32, $y_size, $x_size, $path_len>::new(&sig_y, &sig_x, loss_fns::dist)
+              .constrain(itakura)
+              .try_distance();
+          let guided = DtwSolver::<f32, $y_size, $x_size, $path_len>::new(
+            &sig_y,
+            &sig_x,
+            downsample_fns::mean_u8,
+            loss_fns::dist,
+          )
+          .constrain(itakura)
+          .try_distance();
+
+          // The guided solve may not find a way through, but neither panics
+          let exact = exact.unwrap();
+          if let Ok(guided) = guided {
+            assert!(guided >= exact);
           }
         }
-      }
-    };
+      };
+    }
 
-    self.get_best_path()
+    solve_at_slopes!(14, 8, 21);
+    solve_at_slopes!(12, 14, 25);
+    solve_at_slopes!(8, 32, 39);
   }
 
-  #[inline]
-  pub fn calc_path_cell(&mut self, y: usize, x: usize) {
-    if y == self.signal_size - 1 && x == self.signal_size - 1 {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x],
-        to_parent: Move::Stop,
-      };
-      return;
-    }
+  #[test]
+  fn measures_distance_without_path() {
+    let (sig_y, sig_x) = (LONG_Y, LONG_X);
 
-    if y == self.signal_size - 1 {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x] + self.path_map[y][x + 1].loss,
-        to_parent: Move::Horizontal,
-      };
-      return;
+    let mut solver =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist);
+    solver.search_radius(1);
+    let result = solver.align();
+
+    assert_eq!(result.path.iter().next().unwrap().loss, result.distance);
+    assert_eq!(result.distance, solver.distance());
+  }
+
+  #[test]
+  fn fits_guided_solve_in_corridor_storage() {
+    let mut sig_y = [0f32; 64];
+    let mut sig_x = [0f32; 48];
+    for (t, sample) in sig_y.iter_mut().enumerate() {
+      *sample = (t % 7) as f32;
+    }
+    for (t, sample) in sig_x.iter_mut().enumerate() {
+      *sample = (t % 5) as f32;
     }
 
-    if x == self.signal_size - 1 {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x] + self.path_map[y + 1][x].loss,
-        to_parent: Move::Vertical,
-      };
-      return;
+    let full =
+      DtwSolver::<f32, 64, 48, 111>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(2)
+        .solve();
+
+    // Far less than the 3072 cells of the full grid
+    let mut cells = [MapCell::EMPTY; 7 * (64 + 48)];
+    let corridor = DtwSolver::<f32, 64, 48, 111, _, _>::with_cells(
+      &sig_y,
+      &sig_x,
+      downsample_fns::mean_u8,
+      loss_fns::dist,
+      &mut cells[..],
+    )
+    .search_radius(2)
+    .solve();
+
+    for (f, c) in full.iter().zip(corridor.iter()) {
+      assert_eq!(f.to_parent, c.to_parent);
+      assert_eq!(f.loss, c.loss);
     }
+  }
 
-    let vertical_loss = match self.loss_map[y + 1][x] == INFINITY {
-      true => INFINITY,
-      false => self.path_map[y + 1][x].loss,
-    };
+  #[test]
+  fn refines_where_the_coarse_path_went_astray() {
+    let (sig_y, sig_x) = astray();
+
+    let exact =
+      crate::ExactDtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, loss_fns::dist).distance();
+    let guided =
+      DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .distance();
+    let refined =
+      DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .refine(4)
+        .distance();
+
+    assert!(exact < guided);
+    assert_eq!(exact, refined);
+  }
 
-    let horizontal_loss = match self.loss_map[y][x + 1] == INFINITY {
-      true => INFINITY,
-      false => self.path_map[y][x + 1].loss,
+  #[test]
+  fn refines_distances_within_a_threshold() {
+    let (sig_y, sig_x) = astray();
+    let solver = || {
+      DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
     };
 
-    let diag_loss = match self.loss_map[y + 1][x + 1] == INFINITY {
-      true => INFINITY,
-      false => self.path_map[y + 1][x + 1].loss * 1.4142135,
-    };
+    let guided = solver().search_radius(1).distance();
+    let refined = solver().search_radius(1).refine(4).distance();
+    assert!(refined < guided);
+
+    // A level that makes it under the threshold gets refined as usual, but
+    // one that's abandoned first never gets the chance
+    assert_eq!(
+      DistanceOutcome::Distance(refined),
+      solver().search_radius(1).refine(4).distance_within(guided)
+    );
+    assert_eq!(
+      DistanceOutcome::Abandoned,
+      solver().search_radius(1).refine(4).distance_within(refined)
+    );
+  }
 
-    let min_loss = libm::fminf(vertical_loss, libm::fminf(horizontal_loss, diag_loss));
+  #[test]
+  fn refines_within_corridor_storage() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(29);
+
+    for _ in 0..100 {
+      let mut sig_y = [0f32; 64];
+      let mut sig_x = [0f32; 64];
+      for t in 0..64 {
+        sig_y[t] = rng.gen_range(-5f32, 5f32);
+        sig_x[t] = rng.gen_range(-5f32, 5f32);
+      }
 
-    if diag_loss == min_loss {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x] + diag_loss,
-        to_parent: Move::Diagonal,
-      };
-      return;
+      let exact =
+        crate::ExactDtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, loss_fns::dist).distance();
+      let mut cells = [MapCell::EMPTY; 64 * 24];
+      let refined = DtwSolver::<f32, 64, 64, 127, _, _>::with_cells(
+        &sig_y,
+        &sig_x,
+        downsample_fns::mean_u8,
+        loss_fns::dist,
+        &mut cells[..],
+      )
+      .search_radius(1)
+      .refine(2)
+      .try_solve();
+
+      match refined {
+        Ok(path) => assert!(exact <= path.as_slice()[0].loss),
+        Err(error) => assert!(matches!(error, DtwError::CellCapacityExceeded { .. })),
+      }
     }
+  }
 
-    if vertical_loss == min_loss {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x] + vertical_loss,
-        to_parent: Move::Vertical,
-      };
-      return;
+  #[test]
+  fn evaluates_guided_solve_against_exact() {
+    let (sig_y, sig_x) = astray();
+
+    let exact =
+      crate::ExactDtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, loss_fns::dist).distance();
+    let guided =
+      DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .evaluate();
+    let unguided =
+      DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .evaluate();
+
+    assert_eq!(exact, guided.exact_distance);
+    assert!(guided.relative_error > 0f32);
+    assert!(guided.max_deviation > 0);
+    assert!(guided.in_corridor < 1f32);
+
+    assert_eq!(exact, unguided.guided_distance);
+    assert_eq!(0f32, unguided.relative_error);
+    assert_eq!(0f32, unguided.mean_deviation);
+    assert_eq!(1f32, unguided.in_corridor);
+  }
+
+  #[test]
+  fn shrinks_by_larger_factors() {
+    let mut sig_y = [0f32; 64];
+    let mut sig_x = [0f32; 64];
+    for t in 0..64 {
+      sig_y[t] = (t % 11) as f32;
+      sig_x[t] = ((t + 3) % 11) as f32;
+    }
+    let exact =
+      crate::ExactDtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, loss_fns::dist).distance();
+
+    for factor in [3, 4, 8].iter() {
+      let radius = 1;
+      let mut cells = [MapCell::EMPTY; 23 * 128];
+      let cells = &mut cells[..(2 * radius + 2 * factor - 1) * 128];
+      let guided = DtwSolver::<f32, 64, 64, 127, _, _>::with_cells(
+        &sig_y,
+        &sig_x,
+        downsample_fns::mean_u8,
+        loss_fns::dist,
+        cells,
+      )
+      .downsample_factor(*factor)
+      .search_radius(radius)
+      .distance();
+      let wide =
+        DtwSolver::<f32, 64, 64, 127>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+          .downsample_factor(*factor)
+          .search_radius(64)
+          .distance();
+
+      assert!(exact <= guided);
+      assert_eq!(exact, wide);
     }
+  }
 
-    if horizontal_loss == min_loss {
-      self.path_map[y][x] = PathPoint {
-        loss: self.loss_map[y][x] + horizontal_loss,
-        to_parent: Move::Horizontal,
-      };
-      return;
+  #[test]
+  fn reports_errors_instead_of_panicking() {
+    let sig_y = [1f32, 3f32, 1f32, 5f32, 2f32, 2f32, 4f32, 1f32];
+    let sig_x = [1f32, 1f32, 3f32, 3f32, 1f32, 1f32, 5f32, 5f32];
+
+    let mut cells = [MapCell::EMPTY; 20];
+    let mut solver = DtwSolver::<f32, 8, 8, 15, _, _>::with_cells(
+      &sig_y,
+      &sig_x,
+      downsample_fns::mean_u8,
+      loss_fns::dist,
+      &mut cells[..],
+    );
+    solver.limit_downsamples(0);
+    let error = DtwError::CellCapacityExceeded {
+      needed: 64,
+      capacity: 20,
+    };
+
+    assert_eq!(error, solver.try_solve().unwrap_err());
+    assert_eq!(error, solver.try_align().unwrap_err());
+    assert_eq!(error, solver.try_distance().unwrap_err());
+    assert_eq!(error, solver.try_distance_within(0f32).unwrap_err());
+    assert_eq!(error, solver.try_solve_subsequence().unwrap_err());
+  }
+
+  #[test]
+  fn counts_maps_in_struct_size() {
+    let full = DtwSolver::<f32, 64, 48, 111>::struct_size();
+    let corridor = DtwSolver::<f32, 64, 48, 111, f32, [MapCell; 7 * (64 + 48)]>::struct_size();
+
+    assert!(full > core::mem::size_of::<[[MapCell; 48]; 64]>());
+    assert_eq!(
+      full - corridor,
+      core::mem::size_of::<MapCell>() * (64 * 48 - 7 * (64 + 48))
+    );
+  }
+
+  #[test]
+  fn counts_paths_in_stack_footprint() {
+    type Solver<const MAX_PATH_LEN: usize> = DtwSolver<'static, f32, 64, 48, MAX_PATH_LEN>;
+    let levels = core::mem::size_of::<StackVec<(&[f32], &[f32]), 17>>();
+    let longer_paths = Solver::<121>::stack_footprint() - Solver::<121>::struct_size();
+    let paths = Solver::<111>::stack_footprint() - Solver::<111>::struct_size();
+
+    assert!(paths > levels + 4 * 111 * core::mem::size_of::<PathPoint>());
+    // Four paths of 10 more points each
+    assert_eq!(
+      4 * 10 * core::mem::size_of::<PathPoint>(),
+      longer_paths - paths
+    );
+  }
+
+  #[test]
+  fn accepts_closures_with_state() {
+    let (sig_y, sig_x) = (LONG_Y, LONG_X);
+
+    let plain =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .align();
+
+    // Doubling every loss is exact in floating point, so it can't change
+    // which path wins.
+    let weight = 2f32;
+    let scale = 0.5f32;
+    let weighted = DtwSolver::<f32, 16, 16, 31, _, _, _, _>::new(
+      &sig_y,
+      &sig_x,
+      |samples: &[f32]| samples.iter().sum::<f32>() * scale,
+      |y: &f32, x: &f32| weight * loss_fns::dist(y, x),
+    )
+    .search_radius(1)
+    .align();
+
+    assert_eq!(2f32 * plain.distance, weighted.distance);
+    for (p, w) in plain.path.iter().zip(weighted.path.iter()) {
+      assert_eq!(p.to_parent, w.to_parent);
     }
   }
 
-  #[inline]
-  pub fn get_best_path(&self) -> Path<MAX_PATH_LEN> {
-    let mut y = 0;
-    let mut x = 0;
-    let mut current_cell = self.path_map[y][x];
-    let mut path = Path::empty(false);
-    //let mut len = 0;
-    //let mut moves: [PathPoint; MAX_PATH_LEN] = alloc(false);
-
-    loop {
-      match current_cell.to_parent {
-        Move::Vertical => {
-          y += 1;
-        }
-        Move::Horizontal => {
-          x += 1;
-        }
-        Move::Diagonal => {
-          y += 1;
-          x += 1;
-        }
-        Move::Stop => {}
-      }
+  #[test]
+  fn compares_derivatives_of_each_level() {
+    let mut sig_y = [0f32; 32];
+    let mut sig_x = [0f32; 32];
+    let mut sig_y_2d = [[0f32; 2]; 32];
+    let mut sig_x_2d = [[0f32; 2]; 32];
+    for t in 0..32 {
+      sig_y[t] = (t % 9) as f32;
+      sig_x[t] = ((t + 2) % 9) as f32;
+      sig_y_2d[t] = [sig_y[t], -sig_y[t]];
+      sig_x_2d[t] = [sig_x[t], -sig_x[t]];
+    }
+    let mut sig_x_offset = sig_x;
+    for sample in sig_x_offset.iter_mut() {
+      *sample += 10f32;
+    }
 
-      path.push(current_cell);
+    let guided =
+      DtwSolver::<f32, 32, 32, 63>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .derivatives()
+        .distance();
+    let offset = DtwSolver::<f32, 32, 32, 63>::new(
+      &sig_y,
+      &sig_x_offset,
+      downsample_fns::mean_u8,
+      loss_fns::dist,
+    )
+    .search_radius(1)
+    .derivatives()
+    .distance();
+    let unguided =
+      DtwSolver::<f32, 32, 32, 63>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .derivatives()
+        .distance();
+    let exact = crate::ExactDtwSolver::<f32, 32, 32, 63>::new(&sig_y, &sig_x, loss_fns::dist)
+      .derivatives()
+      .distance();
+    let multi = DtwSolver::<[f32; 2], 32, 32, 63>::new(
+      &sig_y_2d,
+      &sig_x_2d,
+      downsample_fns::mean::<2>,
+      loss_fns::euclidean::<2>,
+    )
+    .search_radius(1)
+    .derivatives()
+    .distance();
+
+    // Derivatives don't see the offset between the signals
+    assert_eq!(guided, offset);
+    assert_eq!(exact, unguided);
+    assert!(exact <= guided);
+    assert!(multi > guided);
+  }
 
-      current_cell = self.path_map[y][x];
-      if current_cell.to_parent == Move::Stop {
-        break;
+  #[test]
+  fn penalizes_phase_shifts_at_every_level() {
+    let (sig_y, sig_x) = (SPIKE_Y, SPIKE_X);
+    let weight = PhaseWeight {
+      g: 1f32,
+      w_max: 1f32,
+    };
+    let max_shift = |path: &Path<31>| {
+      let (mut y, mut x, mut max_shift) = (0i32, 0i32, 0i32);
+      for point in path.iter() {
+        let (dy, dx) = point.to_parent.offset();
+        y += dy as i32;
+        x += dx as i32;
+        max_shift = core::cmp::max(max_shift, (y - x).abs());
       }
-    }
+      max_shift
+    };
+
+    let plain =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .solve();
+    let unguided =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .phase_weight(weight)
+        .align();
+    let guided =
+      DtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .phase_weight(weight)
+        .distance();
+    let exact = crate::ExactDtwSolver::<f32, 16, 16, 31>::new(&sig_y, &sig_x, loss_fns::dist)
+      .phase_weight(weight)
+      .distance();
+
+    // Matching the peaks takes a shift of half the signal, which the weight
+    // makes costlier than leaving them unmatched
+    assert!(max_shift(&unguided.path) < max_shift(&plain));
+    assert_eq!(exact, unguided.distance);
+    assert!(exact <= guided);
+  }
+
+  #[test]
+  fn finds_subsequence_in_longer_signal() {
+    let query = [1f32, 4f32, 6f32, 2f32, 0f32, 3f32, 5f32, 1f32];
+    let mut reference = [0f32; 64];
+    reference[36..44].copy_from_slice(&query);
+
+    let found =
+      DtwSolver::<f32, 8, 64, 71>::new(&query, &reference, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .solve_subsequence();
 
-    path
+    assert_eq!(36, found.start);
+    assert_eq!(43, found.end);
+    assert_eq!(0f32, found.loss);
   }
 }
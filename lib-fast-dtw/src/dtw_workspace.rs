@@ -0,0 +1,553 @@
+use crate::alloc::{alloc, zeroed};
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::cost_map::{CostMap, MapCell};
+use crate::derivative::{derive, Derivative, DeriveFn};
+use crate::downsample::Downsample;
+use crate::downsample_fns::Downsampler;
+use crate::error::{or_panic, DtwError};
+use crate::evaluation::{self, Evaluation};
+use crate::loss_fns::Loss;
+use crate::path::*;
+use crate::phase_weight::PhaseWeight;
+use crate::pyramid::{self, Guide};
+use crate::stack_vec::StackVec;
+use crate::step_pattern::StepPattern;
+use crate::storage::Storage;
+use crate::window::Row;
+
+pub(crate) const MAX_DOWNSAMPLES: usize = 16;
+
+/// Everything `DtwSolver` needs to solve a pair of signals apart from the
+/// signals themselves: the maps, the downsample pyramids of both signals,
+/// the derivatives and the settings. A workspace is built once and then
+/// solves one pair of signals after another, like the rows of a pair of
+/// stereo images, without building any of it again.
+///
+/// `new` and `with_cells` are `const fn`s, so a workspace can live in a
+/// `static` rather than on the stack:
+///
+/// `static mut WORKSPACE: DtwWorkspace<f32, 64, 64, 127> =
+/// DtwWorkspace::new(downsample_fns::mean_u8, loss_fns::dist);`
+///
+/// Like `DtwSolver`, it keeps its cells in `Cells`, which defaults to room
+/// for the full grid, adds up losses in `C`, and takes anything that
+/// implements `Loss` and `Downsampler` once the remaining parameters are
+/// left to inference.
+pub struct DtwWorkspace<
+  SampleType,
+  const SIGNAL_Y_SIZE: usize,
+  const SIGNAL_X_SIZE: usize,
+  const MAX_PATH_LEN: usize,
+  C: Cost = f32,
+  Cells: Storage<MapCell<C>> = [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+  LossFn: Loss<SampleType, C> = fn(&SampleType, &SampleType) -> C,
+  DownsampleFn: Downsampler<SampleType> = fn(&[SampleType]) -> SampleType,
+> {
+  downsample_fn: DownsampleFn,
+  loss_fn: LossFn,
+  derive_fn: Option<DeriveFn<SampleType>>,
+  cost_map: CostMap<C, Cells, [Row; SIGNAL_Y_SIZE]>,
+  downsamples_y: StackVec<Downsample<SampleType, SIGNAL_Y_SIZE>, MAX_DOWNSAMPLES>,
+  downsamples_x: StackVec<Downsample<SampleType, SIGNAL_X_SIZE>, MAX_DOWNSAMPLES>,
+  derivative_y: [SampleType; SIGNAL_Y_SIZE],
+  derivative_x: [SampleType; SIGNAL_X_SIZE],
+  downsample_limit: Option<usize>,
+  downsample_factor: usize,
+  radius: usize,
+  constraint: Option<GlobalConstraint>,
+  phase_weight: Option<PhaseWeight>,
+  refine: Option<usize>,
+}
+impl<
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    LossFn: Loss<SampleType, C>,
+    DownsampleFn: Downsampler<SampleType>,
+  >
+  DtwWorkspace<
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+    LossFn,
+    DownsampleFn,
+  >
+{
+  pub const fn new(downsample_fn: DownsampleFn, loss_fn: LossFn) -> Self {
+    DtwWorkspace::with_cells(downsample_fn, loss_fn, zeroed())
+  }
+}
+impl<
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    Cells: Storage<MapCell<C>>,
+    LossFn: Loss<SampleType, C>,
+    DownsampleFn: Downsampler<SampleType>,
+  >
+  DtwWorkspace<
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    Cells,
+    LossFn,
+    DownsampleFn,
+  >
+{
+  /// Fails the build if the const parameters can't work together. It's only
+  /// evaluated once it's used, so `assemble` refers to it.
+  const VALID_PARAMS: () = {
+    assert!(
+      SIGNAL_Y_SIZE > 0 && SIGNAL_X_SIZE > 0,
+      "both signals need at least one sample"
+    );
+    assert!(
+      MAX_PATH_LEN >= SIGNAL_Y_SIZE + SIGNAL_X_SIZE - 1,
+      "MAX_PATH_LEN must be at least SIGNAL_Y_SIZE + SIGNAL_X_SIZE - 1, \
+       the length of the longest path"
+    );
+    // Halving stops once a signal is shorter than 4 samples, and bigger
+    // factors take fewer downsamples to get there. A longer signal would run
+    // out of downsamples first, and leave a smallest level that's too big to
+    // solve in full cheaply.
+    assert!(
+      SIGNAL_Y_SIZE < 4 << MAX_DOWNSAMPLES && SIGNAL_X_SIZE < 4 << MAX_DOWNSAMPLES,
+      "signals this long need more downsamples than the solver has room for"
+    );
+  };
+
+  /// Creates a workspace that keeps the cells of its maps in `cells`, which
+  /// only has to hold the cells inside of the largest window it evaluates,
+  /// like `DtwSolver::with_cells`. Everything else starts out zeroed, so
+  /// that it can be built at compile time.
+  pub const fn with_cells(downsample_fn: DownsampleFn, loss_fn: LossFn, cells: Cells) -> Self {
+    DtwWorkspace::assemble(
+      downsample_fn,
+      loss_fn,
+      cells,
+      StackVec::zeroed(),
+      StackVec::zeroed(),
+      zeroed(),
+      zeroed(),
+    )
+  }
+
+  /// Like `with_cells`, but leaves the downsamples and derivatives
+  /// uninitialized rather than zeroing them, since every solve overwrites
+  /// them before they're read anyway. For a workspace that only lives as
+  /// long as a single `DtwSolver`.
+  pub(crate) fn with_cells_uninit(
+    downsample_fn: DownsampleFn,
+    loss_fn: LossFn,
+    cells: Cells,
+  ) -> Self {
+    DtwWorkspace::assemble(
+      downsample_fn,
+      loss_fn,
+      cells,
+      StackVec::empty(false),
+      StackVec::empty(false),
+      alloc(false),
+      alloc(false),
+    )
+  }
+
+  const fn assemble(
+    downsample_fn: DownsampleFn,
+    loss_fn: LossFn,
+    cells: Cells,
+    downsamples_y: StackVec<Downsample<SampleType, SIGNAL_Y_SIZE>, MAX_DOWNSAMPLES>,
+    downsamples_x: StackVec<Downsample<SampleType, SIGNAL_X_SIZE>, MAX_DOWNSAMPLES>,
+    derivative_y: [SampleType; SIGNAL_Y_SIZE],
+    derivative_x: [SampleType; SIGNAL_X_SIZE],
+  ) -> Self {
+    #[allow(clippy::let_unit_value)]
+    let () = Self::VALID_PARAMS;
+
+    DtwWorkspace {
+      downsample_fn,
+      loss_fn,
+      derive_fn: None,
+      cost_map: CostMap::new(cells, [Row::EMPTY; SIGNAL_Y_SIZE]),
+      downsamples_y,
+      downsamples_x,
+      derivative_y,
+      derivative_x,
+      downsample_limit: None,
+      downsample_factor: 2,
+      radius: 0,
+      constraint: None,
+      phase_weight: None,
+      refine: None,
+    }
+  }
+
+  /// See `DtwSolver::limit_downsamples`.
+  pub fn limit_downsamples(&mut self, max_downsamples: usize) -> &mut Self {
+    self.downsample_limit = Some(max_downsamples);
+    self
+  }
+
+  /// See `DtwSolver::downsample_factor`.
+  pub fn downsample_factor(&mut self, factor: usize) -> &mut Self {
+    self.downsample_factor = core::cmp::max(factor, 2);
+    self
+  }
+
+  /// See `DtwSolver::search_radius`.
+  pub fn search_radius(&mut self, radius: usize) -> &mut Self {
+    self.radius = radius;
+    self
+  }
+
+  /// See `DtwSolver::refine`.
+  pub fn refine(&mut self, margin: usize) -> &mut Self {
+    self.refine = Some(margin);
+    self
+  }
+
+  /// See `DtwSolver::constrain`.
+  pub fn constrain(&mut self, constraint: GlobalConstraint) -> &mut Self {
+    self.constraint = Some(constraint);
+    self
+  }
+
+  /// See `DtwSolver::phase_weight`.
+  pub fn phase_weight(&mut self, phase_weight: PhaseWeight) -> &mut Self {
+    self.phase_weight = Some(phase_weight);
+    self
+  }
+
+  /// See `DtwSolver::step_pattern`.
+  pub fn step_pattern(&mut self, step_pattern: StepPattern) -> &mut Self {
+    self.cost_map.use_step_pattern(step_pattern);
+    self
+  }
+
+  /// See `DtwSolver::derivatives`.
+  pub fn derivatives(&mut self) -> &mut Self
+  where
+    SampleType: Derivative,
+  {
+    self.derive_fn = Some(derive::<SampleType>);
+    self
+  }
+
+  /// Rebuilds the downsample pyramids of the signals in place and hands
+  /// their levels to `solve`, along with the cost map and guide to solve
+  /// them with. With `derivatives`, the levels are replaced by their
+  /// derivatives first.
+  #[inline]
+  #[allow(clippy::type_complexity)]
+  fn solve_levels<R>(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+    solve: impl FnOnce(
+      &mut CostMap<C, Cells, [Row; SIGNAL_Y_SIZE]>,
+      &[(&[SampleType], &[SampleType])],
+      &Guide<LossFn>,
+    ) -> Result<R, DtwError>,
+  ) -> Result<R, DtwError> {
+    Downsample::shrink_all(
+      &mut self.downsamples_y,
+      sig_y,
+      self.downsample_factor,
+      &self.downsample_fn,
+      self.downsample_limit,
+    );
+    Downsample::shrink_all(
+      &mut self.downsamples_x,
+      sig_x,
+      self.downsample_factor,
+      &self.downsample_fn,
+      self.downsample_limit,
+    );
+
+    let levels = match self.derive_fn {
+      Some(derive_fn) => {
+        derive_fn(sig_y, &mut self.derivative_y);
+        derive_fn(sig_x, &mut self.derivative_x);
+        for i in 0..self.downsamples_y.len() {
+          self.downsamples_y[i].derive(derive_fn);
+        }
+        for i in 0..self.downsamples_x.len() {
+          self.downsamples_x[i].derive(derive_fn);
+        }
+        stack_levels(
+          &self.derivative_y,
+          &self.derivative_x,
+          &self.downsamples_y,
+          &self.downsamples_x,
+          true,
+        )
+      }
+      None => stack_levels(
+        sig_y,
+        sig_x,
+        &self.downsamples_y,
+        &self.downsamples_x,
+        false,
+      ),
+    };
+
+    let guide = Guide {
+      loss_fn: &self.loss_fn,
+      factor: self.downsample_factor,
+      radius: self.radius,
+      constraint: self.constraint,
+      phase_weight: self.phase_weight,
+      refine: self.refine,
+    };
+    solve(&mut self.cost_map, levels.as_slice(), &guide)
+  }
+
+  #[inline]
+  pub fn solve(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Path<MAX_PATH_LEN, C> {
+    or_panic(self.try_solve(sig_y, sig_x))
+  }
+
+  /// Like `solve`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_solve(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Result<Path<MAX_PATH_LEN, C>, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      pyramid::solve(cost_map, levels, guide)
+    })
+  }
+
+  /// Like `solve`, but also returns the distance between the signals.
+  #[inline]
+  pub fn align(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> DtwResult<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_align(sig_y, sig_x))
+  }
+
+  /// Like `align`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_align(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Result<DtwResult<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      pyramid::align(cost_map, levels, guide)
+    })
+  }
+
+  /// See `DtwSolver::distance`.
+  #[inline]
+  pub fn distance(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> C {
+    or_panic(self.try_distance(sig_y, sig_x))
+  }
+
+  /// Like `distance`, but returns an error rather than panicking if the
+  /// distance can't be found.
+  #[inline]
+  pub fn try_distance(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Result<C, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      pyramid::distance::<_, _, _, _, Path<MAX_PATH_LEN, C>>(cost_map, levels, guide)
+    })
+  }
+
+  /// See `DtwSolver::distance_within`.
+  #[inline]
+  pub fn distance_within(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+    best_so_far: C,
+  ) -> DistanceOutcome<C> {
+    or_panic(self.try_distance_within(sig_y, sig_x, best_so_far))
+  }
+
+  /// Like `distance_within`, but returns an error rather than panicking if
+  /// the distance can't be found.
+  #[inline]
+  pub fn try_distance_within(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+    best_so_far: C,
+  ) -> Result<DistanceOutcome<C>, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      pyramid::distance_within::<_, _, _, _, Path<MAX_PATH_LEN, C>>(
+        cost_map,
+        levels,
+        guide,
+        best_so_far,
+      )
+    })
+  }
+
+  /// See `DtwSolver::evaluate`.
+  #[inline]
+  pub fn evaluate(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Evaluation<C> {
+    or_panic(self.try_evaluate(sig_y, sig_x))
+  }
+
+  /// Like `evaluate`, but returns an error rather than panicking if either
+  /// solve fails.
+  #[inline]
+  pub fn try_evaluate(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Result<Evaluation<C>, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      evaluation::evaluate::<_, _, _, _, Path<MAX_PATH_LEN, C>>(cost_map, levels, guide)
+    })
+  }
+
+  /// See `DtwSolver::solve_subsequence`.
+  #[inline]
+  pub fn solve_subsequence(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Subsequence<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_solve_subsequence(sig_y, sig_x))
+  }
+
+  /// Like `solve_subsequence`, but returns an error rather than panicking
+  /// if the match can't be found.
+  #[inline]
+  pub fn try_solve_subsequence(
+    &mut self,
+    sig_y: &[SampleType; SIGNAL_Y_SIZE],
+    sig_x: &[SampleType; SIGNAL_X_SIZE],
+  ) -> Result<Subsequence<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self.solve_levels(sig_y, sig_x, |cost_map, levels, guide| {
+      pyramid::solve_subsequence(cost_map, levels, guide)
+    })
+  }
+}
+
+/// Lines up the downsamples of both signals into the levels of a pyramid,
+/// smallest first, ending with the signals themselves. The signals can be
+/// different lengths, so one of them may run out of downsamples first. Only
+/// the levels that exist for both are usable. With `derived`, the levels
+/// are the derivatives of the downsamples instead.
+#[inline]
+#[allow(clippy::type_complexity)]
+fn stack_levels<'a, SampleType, const SIGNAL_Y_SIZE: usize, const SIGNAL_X_SIZE: usize>(
+  sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+  sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+  downsamples_y: &'a StackVec<Downsample<SampleType, SIGNAL_Y_SIZE>, MAX_DOWNSAMPLES>,
+  downsamples_x: &'a StackVec<Downsample<SampleType, SIGNAL_X_SIZE>, MAX_DOWNSAMPLES>,
+  derived: bool,
+) -> StackVec<(&'a [SampleType], &'a [SampleType]), { MAX_DOWNSAMPLES + 1 }> {
+  let mut levels = StackVec::empty(false);
+  let ds_levels = core::cmp::min(downsamples_y.len(), downsamples_x.len());
+
+  for mi in 0..ds_levels {
+    let i = ds_levels - mi - 1;
+    levels.push(match derived {
+      true => (downsamples_y[i].derivative(), downsamples_x[i].derivative()),
+      false => (
+        &downsamples_y[i].signal[..downsamples_y[i].len],
+        &downsamples_x[i].signal[..downsamples_x[i].len],
+      ),
+    });
+  }
+  levels.push((&sig_y[..], &sig_x[..]));
+
+  levels
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::{downsample_fns, loss_fns, DtwSolver};
+
+  static mut WORKSPACE: DtwWorkspace<f32, 64, 48, 111> =
+    DtwWorkspace::new(downsample_fns::mean_u8, loss_fns::dist);
+
+  fn signals(offset: usize) -> ([f32; 64], [f32; 48]) {
+    let mut sig_y = [0f32; 64];
+    let mut sig_x = [0f32; 48];
+    for (t, sample) in sig_y.iter_mut().enumerate() {
+      *sample = ((t + offset) % 7) as f32;
+    }
+    for (t, sample) in sig_x.iter_mut().enumerate() {
+      *sample = ((t * 3 + offset) % 5) as f32;
+    }
+    (sig_y, sig_x)
+  }
+
+  #[test]
+  fn solves_pair_after_pair_from_a_static() {
+    let workspace = unsafe { &mut *core::ptr::addr_of_mut!(WORKSPACE) };
+    workspace.search_radius(1);
+
+    for offset in 0..4 {
+      let (sig_y, sig_x) = signals(offset);
+      let reused = workspace.align(&sig_y, &sig_x);
+      let fresh =
+        DtwSolver::<f32, 64, 48, 111>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+          .search_radius(1)
+          .align();
+
+      assert_eq!(fresh.distance, reused.distance);
+      for (f, r) in fresh.path.iter().zip(reused.path.iter()) {
+        assert_eq!(f.to_parent, r.to_parent);
+      }
+    }
+  }
+
+  #[test]
+  fn keeps_settings_between_solves() {
+    let mut cells = [MapCell::EMPTY; 64 * 48];
+    let mut workspace = DtwWorkspace::<f32, 64, 48, 111, _, _>::with_cells(
+      downsample_fns::mean_u8,
+      loss_fns::dist,
+      &mut cells[..],
+    );
+    workspace.limit_downsamples(0).derivatives();
+
+    let (sig_y, sig_x) = signals(1);
+    let first = workspace.distance(&sig_y, &sig_x);
+    let again = workspace.distance(&sig_y, &sig_x);
+    let exact = crate::ExactDtwSolver::<f32, 64, 48, 111>::new(&sig_y, &sig_x, loss_fns::dist)
+      .derivatives()
+      .distance();
+
+    assert_eq!(exact, first);
+    assert_eq!(exact, again);
+  }
+}
@@ -0,0 +1,41 @@
+/// Why a solve failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DtwError {
+  /// The path has more points than the `capacity` it has room for. Every
+  /// path between signals of lengths `N` and `M` fits in `N + M - 1`.
+  PathCapacityExceeded { capacity: usize },
+  /// The window covers `needed` cells, but the cell storage only has room
+  /// for `capacity`.
+  CellCapacityExceeded { needed: usize, capacity: usize },
+  /// One of the signals has no samples.
+  SignalTooShort,
+  /// The path found on a downsample leaves its grid, so it can't be used to
+  /// guide the solve of the next level.
+  InvalidGuidePath,
+}
+impl core::fmt::Display for DtwError {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    match *self {
+      DtwError::PathCapacityExceeded { capacity } => {
+        write!(f, "the path doesn't fit in {} points", capacity)
+      }
+      DtwError::CellCapacityExceeded { needed, capacity } => write!(
+        f,
+        "the window covers {} cells, but there's only storage for {}",
+        needed, capacity
+      ),
+      DtwError::SignalTooShort => write!(f, "a signal has no samples"),
+      DtwError::InvalidGuidePath => write!(f, "the downsample path leaves its grid"),
+    }
+  }
+}
+
+/// Unwraps the result of a solve, for the methods that panic rather than
+/// return errors.
+#[inline]
+pub(crate) fn or_panic<T>(result: Result<T, DtwError>) -> T {
+  match result {
+    Ok(value) => value,
+    Err(error) => panic!("failed to solve: {}", error),
+  }
+}
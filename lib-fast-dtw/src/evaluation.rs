@@ -0,0 +1,192 @@
+use crate::cost::Cost;
+use crate::cost_map::{CostMap, MapCell};
+use crate::error::DtwError;
+use crate::loss_fns::Loss;
+use crate::path::{DtwResult, PathBuffer, PathPoint};
+use crate::pyramid::{self, Guide};
+use crate::storage::Storage;
+use crate::window::Row;
+
+/// How far a guided solve landed from the exact one on the same pair of
+/// signals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Evaluation<C = f32> {
+  /// The distance along the path the guided solve found
+  pub guided_distance: C,
+  /// The distance along the optimal path
+  pub exact_distance: C,
+  /// How much lossier the guided path is than the optimal one, as a
+  /// fraction of the optimal distance. 0 when the guided solve found an
+  /// optimal path.
+  pub relative_error: f32,
+  /// The average number of columns between a point on either path and the
+  /// other path, on the same row
+  pub mean_deviation: f32,
+  /// The most columns between a point on either path and the other path, on
+  /// the same row
+  pub max_deviation: usize,
+  /// The fraction of the optimal path's points that lie inside of the
+  /// corridor the guided solve evaluated on the last level. Below 1, the
+  /// corridor cut off the optimal path somewhere.
+  pub in_corridor: f32,
+}
+
+/// Solves the last level of the pyramid exactly, then guided as usual, and
+/// compares the two. The exact solve evaluates every cell of the grid
+/// inside of the global constraint, so the cell storage needs room for all
+/// of them.
+#[inline]
+pub fn evaluate<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<Evaluation<C>, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let (sig_y, sig_x) = levels[levels.len() - 1];
+  cost_map.use_phase_weight(guide.phase_weight);
+  cost_map.guide(None, 0, 0, guide.constraint, sig_y.len(), sig_x.len())?;
+  let exact: DtwResult<P, C> = cost_map.align(sig_y, sig_x, guide.loss_fn)?;
+
+  // The guided solve leaves the window of its last level in the cost map,
+  // which is the corridor the optimal path gets checked against
+  let guided: DtwResult<P, C> = pyramid::align(cost_map, levels, guide)?;
+  let in_corridor = points(exact.path.points())
+    .filter(|&(y, x)| cost_map.in_window(y, x))
+    .count();
+
+  let (guided_total, guided_max) = deviation(guided.path.points(), exact.path.points());
+  let (exact_total, exact_max) = deviation(exact.path.points(), guided.path.points());
+  let point_count = guided.path.points().len() + exact.path.points().len();
+
+  Ok(Evaluation {
+    guided_distance: guided.distance,
+    exact_distance: exact.distance,
+    relative_error: relative_error(guided.distance, exact.distance),
+    mean_deviation: (guided_total + exact_total) as f32 / point_count as f32,
+    max_deviation: core::cmp::max(guided_max, exact_max),
+    in_corridor: in_corridor as f32 / exact.path.points().len() as f32,
+  })
+}
+
+/// How much greater `guided` is than `exact`, as a fraction of `exact`.
+#[inline]
+fn relative_error<C: Cost>(guided: C, exact: C) -> f32 {
+  let (guided, exact) = (guided.to_f32(), exact.to_f32());
+  match exact == 0f32 {
+    true => match guided == 0f32 {
+      true => 0f32,
+      false => f32::INFINITY,
+    },
+    false => (guided - exact) / exact,
+  }
+}
+
+/// The cells a path passes through, in order from the top left corner.
+#[inline]
+fn points<C>(path: &[PathPoint<C>]) -> impl Iterator<Item = (usize, usize)> + '_ {
+  path.iter().scan((0, 0), |cell, point| {
+    let here = *cell;
+    let (dy, dx) = point.to_parent.offset();
+    *cell = (cell.0 + dy, cell.1 + dx);
+    Some(here)
+  })
+}
+
+/// The total and the greatest number of columns between each point of
+/// `path` and the columns `other` spans on the same row. Step patterns
+/// that constrain the slope can skip rows, so a skipped row counts as
+/// spanning the columns between the points before and after it.
+fn deviation<C>(path: &[PathPoint<C>], other: &[PathPoint<C>]) -> (usize, usize) {
+  let mut after = points(other).peekable();
+  let mut before = points(other).peekable();
+  let mut last_before = (0, 0);
+  let (mut total, mut max) = (0, 0);
+
+  for (y, x) in points(path) {
+    while let Some(&(other_y, _)) = after.peek() {
+      if other_y >= y {
+        break;
+      }
+      after.next();
+    }
+    while let Some(&(other_y, other_x)) = before.peek() {
+      if other_y > y {
+        break;
+      }
+      last_before = (other_y, other_x);
+      before.next();
+    }
+
+    let first_x = after.peek().map_or(last_before.1, |&(_, other_x)| other_x);
+    let span = (
+      core::cmp::min(first_x, last_before.1),
+      core::cmp::max(first_x, last_before.1),
+    );
+    let deviation = match x < span.0 {
+      true => span.0 - x,
+      false => x.saturating_sub(span.1),
+    };
+    total += deviation;
+    max = core::cmp::max(max, deviation);
+  }
+
+  (total, max)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::path::Move;
+
+  fn path(moves: &[Move]) -> [PathPoint; 8] {
+    let mut points = [PathPoint {
+      loss: 0f32,
+      to_parent: Move::Stop,
+    }; 8];
+    for (point, &to_parent) in points.iter_mut().zip(moves.iter()) {
+      point.to_parent = to_parent;
+    }
+    points
+  }
+
+  #[test]
+  fn measures_deviation_by_row() {
+    use Move::*;
+    let diagonal = path(&[Diagonal, Diagonal, Diagonal, Stop]);
+    let detour = path(&[Horizontal, Horizontal, Diagonal, Vertical, Vertical, Stop]);
+
+    assert_eq!((0, 0), deviation(&diagonal[..4], &diagonal[..4]));
+    // (0, 1) and (0, 2) lie right of (0, 0), (1, 3) right of (1, 1) and
+    // (2, 3) right of (2, 2)
+    assert_eq!((6, 2), deviation(&detour[..6], &diagonal[..4]));
+    // (1, 1) and (2, 2) lie left of (1, 3) and (2, 3)
+    assert_eq!((3, 2), deviation(&diagonal[..4], &detour[..6]));
+  }
+
+  #[test]
+  fn spans_skipped_rows() {
+    let steep = path(&[
+      Move::Step { y: 2, x: 1 },
+      Move::Step { y: 1, x: 2 },
+      Move::Stop,
+    ]);
+    let diagonal = path(&[Move::Diagonal, Move::Diagonal, Move::Diagonal, Move::Stop]);
+
+    // The steep path skips row 1, where it spans columns 0 to 1, so only
+    // (2, 2) strays from it
+    assert_eq!((1, 1), deviation(&diagonal[..4], &steep[..3]));
+  }
+
+  #[test]
+  fn compares_costs_by_ratio() {
+    assert_eq!(0f32, relative_error(0u32, 0u32));
+    assert_eq!(f32::INFINITY, relative_error(1u32, 0u32));
+    assert_eq!(0.25f32, relative_error(5u32, 4u32));
+  }
+}
@@ -0,0 +1,546 @@
+use crate::alloc::alloc;
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::cost_map::{CostMap, MapCell};
+use crate::derivative::{derive, Derivative, DeriveFn};
+use crate::error::{or_panic, DtwError};
+use crate::loss_fns::Loss;
+use crate::path::{DistanceOutcome, DtwResult, Path, Subsequence};
+use crate::phase_weight::PhaseWeight;
+use crate::step_pattern::StepPattern;
+use crate::storage::Storage;
+use crate::window::Row;
+
+/// Solves the full grid between two signals without downsampling them.
+///
+/// Every cell of the grid (or every cell allowed by a global constraint) is
+/// evaluated, so the returned path is the optimal one: no other path through
+/// the allowed cells has a lower loss. It's the same path `DtwSolver`
+/// converges to as its search radius grows to cover the grid, which makes it
+/// useful as ground truth for the approximate solver, or as a fallback when
+/// its path can't be trusted. The price is quadratic time.
+///
+/// The cells of the maps are kept in `Cells`, which defaults to room for
+/// the full grid. Under a global constraint only the cells inside of it are
+/// stored, so `with_cells` can be given less.
+///
+/// Losses are added up in `C`, which defaults to `f32`. Integer and
+/// fixed-point costs like `u32` and `Q16` solve without any floating point
+/// arithmetic, for targets without an FPU.
+///
+/// The loss function defaults to a plain function pointer. Anything that
+/// implements `Loss` works, closures with state included, once the remaining
+/// parameters are left to inference:
+/// `ExactDtwSolver::<f32, 64, 64, 127, _, _, _>::new(..)`.
+pub struct ExactDtwSolver<
+  'a,
+  SampleType,
+  const SIGNAL_Y_SIZE: usize,
+  const SIGNAL_X_SIZE: usize,
+  const MAX_PATH_LEN: usize,
+  C: Cost = f32,
+  Cells: Storage<MapCell<C>> = [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+  LossFn: Loss<SampleType, C> = fn(&SampleType, &SampleType) -> C,
+> {
+  sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+  sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+  loss_fn: LossFn,
+  derive_fn: Option<DeriveFn<SampleType>>,
+  cost_map: CostMap<C, Cells, [Row; SIGNAL_Y_SIZE]>,
+  derivative_y: [SampleType; SIGNAL_Y_SIZE],
+  derivative_x: [SampleType; SIGNAL_X_SIZE],
+  constraint: Option<GlobalConstraint>,
+  phase_weight: Option<PhaseWeight>,
+}
+impl<
+    'a,
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    LossFn: Loss<SampleType, C>,
+  >
+  ExactDtwSolver<
+    'a,
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    MAX_PATH_LEN,
+    C,
+    [[MapCell<C>; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+    LossFn,
+  >
+{
+  pub fn new(
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    loss_fn: LossFn,
+  ) -> Self {
+    ExactDtwSolver::with_cells(sig_y, sig_x, loss_fn, alloc(false))
+  }
+}
+impl<
+    'a,
+    SampleType,
+    const SIGNAL_Y_SIZE: usize,
+    const SIGNAL_X_SIZE: usize,
+    const MAX_PATH_LEN: usize,
+    C: Cost,
+    Cells: Storage<MapCell<C>>,
+    LossFn: Loss<SampleType, C>,
+  > ExactDtwSolver<'a, SampleType, SIGNAL_Y_SIZE, SIGNAL_X_SIZE, MAX_PATH_LEN, C, Cells, LossFn>
+{
+  /// Fails the build if the const parameters can't work together. It's only
+  /// evaluated once it's used, so `with_cells` refers to it.
+  const VALID_PARAMS: () = {
+    assert!(
+      SIGNAL_Y_SIZE > 0 && SIGNAL_X_SIZE > 0,
+      "both signals need at least one sample"
+    );
+    assert!(
+      MAX_PATH_LEN >= SIGNAL_Y_SIZE + SIGNAL_X_SIZE - 1,
+      "MAX_PATH_LEN must be at least SIGNAL_Y_SIZE + SIGNAL_X_SIZE - 1, \
+       the length of the longest path"
+    );
+  };
+
+  /// How many bytes the solver itself takes up, with its maps and
+  /// derivatives.
+  pub const fn struct_size() -> usize {
+    core::mem::size_of::<Self>()
+  }
+
+  /// How many bytes of stack the solver and a solve take up together: the
+  /// solver itself, plus the largest result a solve returns, a subsequence
+  /// match. The compiler's own temporaries aren't counted, so leave some
+  /// headroom when sizing a thread's stack with it.
+  pub const fn stack_footprint() -> usize {
+    Self::struct_size() + core::mem::size_of::<Subsequence<Path<MAX_PATH_LEN, C>, C>>()
+  }
+
+  /// Creates a solver that keeps the cells of its maps in `cells`. It has to
+  /// hold every cell of the grid, or every cell inside of the global
+  /// constraint if there is one, or the solve fails with
+  /// `DtwError::CellCapacityExceeded`.
+  pub fn with_cells(
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    loss_fn: LossFn,
+    cells: Cells,
+  ) -> Self {
+    #[allow(clippy::let_unit_value)]
+    let () = Self::VALID_PARAMS;
+
+    ExactDtwSolver {
+      sig_y,
+      sig_x,
+      loss_fn,
+      derive_fn: None,
+      cost_map: CostMap::new(cells, [Row::EMPTY; SIGNAL_Y_SIZE]),
+      derivative_y: alloc(false),
+      derivative_x: alloc(false),
+      constraint: None,
+      phase_weight: None,
+    }
+  }
+
+  /// Restricts the path to the cells allowed by a global constraint. Cells
+  /// outside of it are never evaluated, and the result is the optimal path
+  /// among those that stay inside.
+  pub fn constrain(&mut self, constraint: GlobalConstraint) -> &mut Self {
+    self.constraint = Some(constraint);
+    self
+  }
+
+  /// Penalizes phase shifts with a logistic weight on every cell's loss
+  /// (weighted DTW).
+  pub fn phase_weight(&mut self, phase_weight: PhaseWeight) -> &mut Self {
+    self.phase_weight = Some(phase_weight);
+    self
+  }
+
+  /// Chooses the moves the path may take and how they're weighted. Defaults
+  /// to `StepPattern::Symmetric2`.
+  pub fn step_pattern(&mut self, step_pattern: StepPattern) -> &mut Self {
+    self.cost_map.use_step_pattern(step_pattern);
+    self
+  }
+
+  /// Compares the derivatives of the signals rather than the signals
+  /// themselves (derivative DTW).
+  pub fn derivatives(&mut self) -> &mut Self
+  where
+    SampleType: Derivative,
+  {
+    self.derive_fn = Some(derive::<SampleType>);
+    self
+  }
+
+  #[inline]
+  pub fn solve(&mut self) -> Path<MAX_PATH_LEN, C> {
+    or_panic(self.try_solve())
+  }
+
+  /// Like `solve`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_solve(&mut self) -> Result<Path<MAX_PATH_LEN, C>, DtwError> {
+    self.guide_all()?;
+    self.solve_signals(|cost_map, sig_y, sig_x, loss_fn| cost_map.solve(sig_y, sig_x, loss_fn))
+  }
+
+  /// Like `solve`, but also returns the distance between the signals.
+  #[inline]
+  pub fn align(&mut self) -> DtwResult<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_align())
+  }
+
+  /// Like `align`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_align(&mut self) -> Result<DtwResult<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self.guide_all()?;
+    self.solve_signals(|cost_map, sig_y, sig_x, loss_fn| cost_map.align(sig_y, sig_x, loss_fn))
+  }
+
+  /// The distance between the signals along the optimal path, without
+  /// tracing the path itself.
+  #[inline]
+  pub fn distance(&mut self) -> C {
+    or_panic(self.try_distance())
+  }
+
+  /// Like `distance`, but returns an error rather than panicking if the
+  /// distance can't be found.
+  #[inline]
+  pub fn try_distance(&mut self) -> Result<C, DtwError> {
+    self.guide_all()?;
+    self.solve_signals(|cost_map, sig_y, sig_x, loss_fn| cost_map.distance(sig_y, sig_x, loss_fn))
+  }
+
+  /// Like `distance`, but gives up as soon as every path left is lossier
+  /// than `best_so_far`, which saves most of the work when searching for a
+  /// nearest neighbour. Losses must not be negative.
+  #[inline]
+  pub fn distance_within(&mut self, best_so_far: C) -> DistanceOutcome<C> {
+    or_panic(self.try_distance_within(best_so_far))
+  }
+
+  /// Like `distance_within`, but returns an error rather than panicking if
+  /// the distance can't be found.
+  #[inline]
+  pub fn try_distance_within(&mut self, best_so_far: C) -> Result<DistanceOutcome<C>, DtwError> {
+    self.guide_all()?;
+    self.solve_signals(|cost_map, sig_y, sig_x, loss_fn| {
+      cost_map.distance_within(sig_y, sig_x, loss_fn, best_so_far)
+    })
+  }
+
+  /// Finds where `sig_y` best matches inside of the longer `sig_x`. The
+  /// match may start and end anywhere along `sig_x`, and is the best match
+  /// among all of them. Global constraints and phase weights don't apply to
+  /// subsequence matches.
+  #[inline]
+  pub fn solve_subsequence(&mut self) -> Subsequence<Path<MAX_PATH_LEN, C>, C> {
+    or_panic(self.try_solve_subsequence())
+  }
+
+  /// Like `solve_subsequence`, but returns an error rather than panicking
+  /// if the match can't be found.
+  #[inline]
+  pub fn try_solve_subsequence(
+    &mut self,
+  ) -> Result<Subsequence<Path<MAX_PATH_LEN, C>, C>, DtwError> {
+    self
+      .cost_map
+      .guide(None, 0, 0, None, SIGNAL_Y_SIZE, SIGNAL_X_SIZE)?;
+    self.cost_map.use_phase_weight(None);
+    self.solve_signals(|cost_map, sig_y, sig_x, loss_fn| {
+      cost_map.solve_subsequence(sig_y, sig_x, loss_fn)
+    })
+  }
+
+  /// Hands the signals to `solve`, along with the cost map and loss function
+  /// to solve them with. With `derivatives`, the signals are replaced by
+  /// their derivatives first.
+  #[inline]
+  #[allow(clippy::type_complexity)]
+  fn solve_signals<R>(
+    &mut self,
+    solve: impl FnOnce(
+      &mut CostMap<C, Cells, [Row; SIGNAL_Y_SIZE]>,
+      &[SampleType],
+      &[SampleType],
+      &LossFn,
+    ) -> Result<R, DtwError>,
+  ) -> Result<R, DtwError> {
+    let (sig_y, sig_x): (&[SampleType], &[SampleType]) = match self.derive_fn {
+      Some(derive_fn) => {
+        derive_fn(self.sig_y, &mut self.derivative_y);
+        derive_fn(self.sig_x, &mut self.derivative_x);
+        (&self.derivative_y, &self.derivative_x)
+      }
+      None => (self.sig_y, self.sig_x),
+    };
+    solve(&mut self.cost_map, sig_y, sig_x, &self.loss_fn)
+  }
+
+  /// Points the cost map at every cell of the grid that the constraint
+  /// allows, weighed by the phase weight.
+  #[inline]
+  fn guide_all(&mut self) -> Result<(), DtwError> {
+    self.cost_map.use_phase_weight(self.phase_weight);
+    self
+      .cost_map
+      .guide(None, 0, 0, self.constraint, SIGNAL_Y_SIZE, SIGNAL_X_SIZE)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::test_signals::{SHORT_X, SHORT_Y};
+  use crate::{downsample_fns, loss_fns, DtwSolver, Move, Q16};
+  use core::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn matches_unguided_dtw_solver() {
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+
+    let exact = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, loss_fns::dist).solve();
+    let unguided =
+      DtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .solve();
+
+    assert_eq!(exact.iter().count(), unguided.iter().count());
+    for (e, u) in exact.iter().zip(unguided.iter()) {
+      assert_eq!(e.to_parent, u.to_parent);
+      assert_eq!(e.loss, u.loss);
+    }
+  }
+
+  #[test]
+  fn fits_itakura_to_a_single_row_or_column() {
+    let row = [2f32];
+    let column = [0f32, 1f32, 5f32, 3f32, 2f32];
+    let itakura = GlobalConstraint::Itakura { max_slope: 2f32 };
+
+    let wide = ExactDtwSolver::<f32, 1, 5, 5>::new(&row, &column, loss_fns::dist)
+      .constrain(itakura)
+      .align();
+    let tall = ExactDtwSolver::<f32, 5, 1, 5>::new(&column, &row, loss_fns::dist)
+      .constrain(itakura)
+      .align();
+
+    assert_eq!(7f32, wide.distance);
+    assert_eq!(7f32, tall.distance);
+  }
+
+  #[test]
+  fn counts_derivatives_in_stack_footprint() {
+    use core::mem::size_of;
+
+    let footprint = ExactDtwSolver::<f32, 64, 48, 111>::stack_footprint();
+    let maps = size_of::<[[MapCell; 48]; 64]>() + size_of::<[Row; 64]>();
+    let derivatives = size_of::<[f32; 64 + 48]>();
+    let result = size_of::<Subsequence<Path<111>>>();
+
+    assert!(footprint >= maps + derivatives + result);
+    assert_eq!(
+      footprint - ExactDtwSolver::<f32, 64, 48, 111>::struct_size(),
+      result
+    );
+  }
+
+  #[test]
+  fn solves_in_any_cost_type() {
+    let sig_y = SHORT_Y.map(|s| s as i32);
+    let sig_x = SHORT_X.map(|s| s as i32);
+
+    let float = ExactDtwSolver::<i32, 10, 8, 17>::new(&sig_y, &sig_x, |y: &i32, x: &i32| {
+      (*y - *x).abs() as f32
+    })
+    .align();
+    let double = ExactDtwSolver::<i32, 10, 8, 17, f64>::new(&sig_y, &sig_x, |y: &i32, x: &i32| {
+      (*y - *x).abs() as f64
+    })
+    .align();
+    let unsigned =
+      ExactDtwSolver::<i32, 10, 8, 17, u32>::new(&sig_y, &sig_x, |y: &i32, x: &i32| y.abs_diff(*x))
+        .align();
+    let signed = ExactDtwSolver::<i32, 10, 8, 17, i32>::new(&sig_y, &sig_x, |y: &i32, x: &i32| {
+      (*y - *x).abs()
+    })
+    .align();
+    let fixed = ExactDtwSolver::<i32, 10, 8, 17, Q16>::new(&sig_y, &sig_x, |y: &i32, x: &i32| {
+      Q16::from_int((*y - *x).abs())
+    })
+    .align();
+
+    assert_eq!(float.distance as f64, double.distance);
+    assert_eq!(float.distance as u32, unsigned.distance);
+    assert_eq!(float.distance as i32, signed.distance);
+    assert_eq!(float.distance, fixed.distance.to_f32());
+    for (i, f) in float.path.iter().enumerate() {
+      assert_eq!(f.to_parent, unsigned.path.iter().nth(i).unwrap().to_parent);
+      assert_eq!(f.to_parent, fixed.path.iter().nth(i).unwrap().to_parent);
+    }
+  }
+
+  #[test]
+  fn normalizes_distance_by_path_weight() {
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+
+    // Every symmetric path weighs N + M - 1 cells, and every asymmetric path
+    // weighs N
+    for &(step_pattern, weight) in [
+      (StepPattern::Symmetric2, 17f32),
+      (StepPattern::Asymmetric, 10f32),
+    ]
+    .iter()
+    {
+      let mut solver = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, loss_fns::dist);
+      solver.step_pattern(step_pattern);
+      let result = solver.align();
+
+      assert_eq!(result.path.iter().next().unwrap().loss, result.distance);
+      assert_eq!(result.distance / weight, result.normalized_distance);
+      assert_eq!(result.distance, solver.distance());
+    }
+  }
+
+  #[test]
+  fn abandons_distances_beyond_best_so_far() {
+    static LOSSES: AtomicUsize = AtomicUsize::new(0);
+    fn counted_dist(a: &f32, b: &f32) -> f32 {
+      LOSSES.fetch_add(1, Ordering::Relaxed);
+      loss_fns::dist(a, b)
+    }
+
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+    let mut solver = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, counted_dist);
+    let distance = solver.distance();
+
+    assert_eq!(
+      DistanceOutcome::Distance(distance),
+      solver.distance_within(distance)
+    );
+    assert_eq!(
+      DistanceOutcome::Abandoned,
+      solver.distance_within(distance - 0.5f32)
+    );
+
+    // A hopeless candidate is given up on after its first row
+    let far_x = [100f32; 8];
+    let mut solver = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &far_x, counted_dist);
+    LOSSES.store(0, Ordering::Relaxed);
+    assert_eq!(DistanceOutcome::Abandoned, solver.distance_within(distance));
+    assert_eq!(8, LOSSES.load(Ordering::Relaxed));
+  }
+
+  #[test]
+  fn finds_exact_subsequence() {
+    let query = [2f32, 5f32, 1f32];
+    let reference = [0f32, 0f32, 1f32, 0f32, 2f32, 5f32, 5f32, 1f32, 0f32, 3f32];
+
+    let found =
+      ExactDtwSolver::<f32, 3, 10, 12>::new(&query, &reference, loss_fns::dist).solve_subsequence();
+
+    assert_eq!(4, found.start);
+    assert_eq!(7, found.end);
+    assert_eq!(0f32, found.loss);
+  }
+
+  /// Textbook forward DTW recursion, for checking the step patterns against
+  fn forward_dtw<const Y: usize, const X: usize>(
+    sig_y: &[f32; Y],
+    sig_x: &[f32; X],
+    step_pattern: StepPattern,
+  ) -> f32 {
+    let inf = f32::INFINITY;
+    let mut g = [[inf; X]; Y];
+    for y in 0..Y {
+      for x in 0..X {
+        let d = |y: usize, x: usize| loss_fns::dist(&sig_y[y], &sig_x[x]);
+        // The cost of reaching (y, x) from `dy` rows and `dx` columns back,
+        // through cells given as `(rows back, columns back, weight)`
+        let from = |g: &[[f32; X]; Y], dy, dx, cells: &[(usize, usize, f32)]| {
+          let reachable = y >= dy && x >= dx;
+          match reachable {
+            true => cells.iter().fold(g[y - dy][x - dx], |cost, &(cy, cx, w)| {
+              cost + w * d(y - cy, x - cx)
+            }),
+            false => inf,
+          }
+        };
+        g[y][x] = match (y, x) {
+          (0, 0) => d(0, 0),
+          _ => match step_pattern {
+            StepPattern::Symmetric1 => from(&g, 1, 1, &[(0, 0, 1f32)])
+              .min(from(&g, 1, 0, &[(0, 0, 1f32)]))
+              .min(from(&g, 0, 1, &[(0, 0, 1f32)])),
+            StepPattern::Symmetric2 | StepPattern::SymmetricP0 => from(&g, 1, 1, &[(0, 0, 2f32)])
+              .min(from(&g, 1, 0, &[(0, 0, 1f32)]))
+              .min(from(&g, 0, 1, &[(0, 0, 1f32)])),
+            StepPattern::Asymmetric => from(&g, 1, 0, &[(0, 0, 1f32)])
+              .min(from(&g, 1, 1, &[(0, 0, 1f32)]))
+              .min(from(&g, 1, 2, &[(0, 0, 1f32)])),
+            StepPattern::SymmetricP1 => from(&g, 1, 1, &[(0, 0, 2f32)])
+              .min(from(&g, 2, 1, &[(1, 0, 2f32), (0, 0, 1f32)]))
+              .min(from(&g, 1, 2, &[(0, 1, 2f32), (0, 0, 1f32)])),
+            StepPattern::SymmetricP2 => from(&g, 1, 1, &[(0, 0, 2f32)])
+              .min(from(&g, 3, 2, &[(2, 1, 2f32), (1, 0, 2f32), (0, 0, 1f32)]))
+              .min(from(&g, 2, 3, &[(1, 2, 2f32), (0, 1, 2f32), (0, 0, 1f32)])),
+          },
+        };
+      }
+    }
+    g[Y - 1][X - 1]
+  }
+
+  #[test]
+  fn follows_step_patterns() {
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+
+    for step_pattern in [
+      StepPattern::Symmetric1,
+      StepPattern::Symmetric2,
+      StepPattern::Asymmetric,
+      StepPattern::SymmetricP0,
+      StepPattern::SymmetricP1,
+      StepPattern::SymmetricP2,
+    ]
+    .iter()
+    {
+      let path = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, loss_fns::dist)
+        .step_pattern(*step_pattern)
+        .solve();
+      let expected = forward_dtw(&sig_y, &sig_x, *step_pattern);
+      assert!((expected - path.iter().next().unwrap().loss).abs() < 1e-4);
+    }
+  }
+
+  #[test]
+  fn slope_constrained_patterns_take_long_steps() {
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+
+    let path = ExactDtwSolver::<f32, 10, 8, 17>::new(&sig_y, &sig_x, loss_fns::dist)
+      .step_pattern(StepPattern::SymmetricP2)
+      .solve();
+
+    let (mut y, mut x) = (0, 0);
+    for point in path.iter() {
+      match point.to_parent {
+        Move::Diagonal | Move::Step { y: 3, x: 2 } | Move::Step { y: 2, x: 3 } => {}
+        other => panic!("unexpected move {:?}", other),
+      }
+      let (dy, dx) = point.to_parent.offset();
+      y += dy;
+      x += dx;
+    }
+    assert_eq!((9, 7), (y, x));
+  }
+}
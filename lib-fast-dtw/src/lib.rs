@@ -1,15 +1,50 @@
-#![feature(min_const_generics)]
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc as alloc_crate;
+
 mod alloc;
+mod constraint;
+mod cost;
+mod cost_map;
 mod downsample;
 mod dtw_solver;
+mod dtw_workspace;
+mod error;
+mod evaluation;
+mod exact_dtw_solver;
+mod phase_weight;
+mod pyramid;
+mod step_pattern;
+mod storage;
+#[cfg(test)]
+mod test_signals;
+#[cfg(feature = "alloc")]
+mod vec_dtw_solver;
+mod window;
 
+pub mod derivative;
 pub mod downsample_fns;
 pub mod loss_fns;
+pub mod lower_bounds;
 pub mod path;
+pub mod soft_dtw;
 pub mod stack_vec;
 
+pub use constraint::GlobalConstraint;
+pub use cost::{Cost, Q16};
+pub use cost_map::MapCell;
+pub use downsample_fns::Downsampler;
 pub use dtw_solver::DtwSolver;
-pub use path::{Move, Path, PathPoint};
+pub use dtw_workspace::DtwWorkspace;
+pub use error::DtwError;
+pub use evaluation::Evaluation;
+pub use exact_dtw_solver::ExactDtwSolver;
+pub use loss_fns::{Loss, LossGradient};
+pub use path::{DistanceOutcome, DtwResult, Move, Path, PathPoint, Subsequence};
+pub use phase_weight::PhaseWeight;
 pub use stack_vec::{StackVec, StackVecIterator};
+pub use step_pattern::StepPattern;
+pub use storage::Storage;
+#[cfg(feature = "alloc")]
+pub use vec_dtw_solver::VecDtwSolver;
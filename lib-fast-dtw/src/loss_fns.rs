@@ -1,5 +1,85 @@
 use libm::sqrtf;
 
+/// Measures how different two samples are, as a `Cost` that defaults to
+/// `f32`. Losses shouldn't be negative.
+///
+/// Implemented for any closure or function that takes two samples and
+/// returns a cost, including the ones in this module, so a loss can carry
+/// its own state, like per-channel weights or a lookup table.
+pub trait Loss<SampleType, C = f32> {
+  fn loss(&self, y: &SampleType, x: &SampleType) -> C;
+}
+impl<SampleType, C, F: Fn(&SampleType, &SampleType) -> C> Loss<SampleType, C> for F {
+  #[inline]
+  fn loss(&self, y: &SampleType, x: &SampleType) -> C {
+    self(y, x)
+  }
+}
+
+/// A loss that can also say how it changes with its samples, for
+/// differentiable alignments like Soft-DTW.
+pub trait LossGradient<SampleType>: Loss<SampleType, f32> {
+  /// Adds `weight` times the gradient of the loss with respect to `y` onto
+  /// `grad_y`, and with respect to `x` onto `grad_x`.
+  fn add_gradient(
+    &self,
+    y: &SampleType,
+    x: &SampleType,
+    weight: f32,
+    grad_y: &mut SampleType,
+    grad_x: &mut SampleType,
+  );
+}
+
+/// The squared difference between two samples, summed over every dimension
+/// of multi-dimensional samples. Smooth everywhere, which makes it the usual
+/// loss for Soft-DTW.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SquaredError;
+
+impl Loss<f32> for SquaredError {
+  #[inline]
+  fn loss(&self, y: &f32, x: &f32) -> f32 {
+    (*y - *x) * (*y - *x)
+  }
+}
+
+impl LossGradient<f32> for SquaredError {
+  #[inline]
+  fn add_gradient(&self, y: &f32, x: &f32, weight: f32, grad_y: &mut f32, grad_x: &mut f32) {
+    let slope = 2f32 * (*y - *x) * weight;
+    *grad_y += slope;
+    *grad_x -= slope;
+  }
+}
+
+impl<const N: usize> Loss<[f32; N]> for SquaredError {
+  #[inline]
+  fn loss(&self, y: &[f32; N], x: &[f32; N]) -> f32 {
+    let mut accum = 0f32;
+    for i in 0..N {
+      accum += SquaredError.loss(&y[i], &x[i]);
+    }
+    accum
+  }
+}
+
+impl<const N: usize> LossGradient<[f32; N]> for SquaredError {
+  #[inline]
+  fn add_gradient(
+    &self,
+    y: &[f32; N],
+    x: &[f32; N],
+    weight: f32,
+    grad_y: &mut [f32; N],
+    grad_x: &mut [f32; N],
+  ) {
+    for i in 0..N {
+      SquaredError.add_gradient(&y[i], &x[i], weight, &mut grad_y[i], &mut grad_x[i]);
+    }
+  }
+}
+
 #[inline]
 pub fn dist(y: &f32, x: &f32) -> f32 {
   libm::fabsf(*y - *x)
@@ -11,5 +91,5 @@ pub fn euclidean<const N: usize>(y: &[f32; N], x: &[f32; N]) -> f32 {
   for i in 0..N {
     accum += libm::powf(dist(&y[i], &x[i]), 2f32);
   }
-  sqrtf(accum as f32)
+  sqrtf(accum)
 }
@@ -0,0 +1,255 @@
+//! Cheap lower bounds on the distance between two signals, for pruning a
+//! search over many candidates without solving each one. A candidate whose
+//! lower bound is already worse than the best distance found so far can be
+//! skipped.
+//!
+//! The bounds hold for every step pattern, as long as losses are never
+//! negative, except where noted otherwise. The query is always the Y signal
+//! and the candidate the X signal.
+
+use crate::alloc::alloc;
+use crate::cost::Cost;
+use crate::loss_fns::Loss;
+
+/// LB_Kim, in its first-and-last form: every path starts on the first pair
+/// of samples and ends on the last, so their losses are a lower bound on the
+/// distance. It's nearly free to compute, and works for signals of
+/// different lengths.
+#[inline]
+pub fn lb_kim<SampleType, C: Cost>(
+  query: &[SampleType],
+  candidate: &[SampleType],
+  loss_fn: impl Loss<SampleType, C>,
+) -> C {
+  let first = loss_fn.loss(&query[0], &candidate[0]);
+  match query.len() == 1 && candidate.len() == 1 {
+    true => first,
+    false => first.plus(loss_fn.loss(&query[query.len() - 1], &candidate[candidate.len() - 1])),
+  }
+}
+
+/// Picks the lower and the higher of two samples, for building envelopes.
+/// For multi-dimensional samples they work a dimension at a time, and so
+/// bound a box around each sample.
+///
+/// Implemented for any pair of closures or functions `(min_fn, max_fn)`
+/// that take two samples and return a new one, like `(min, max)` or
+/// `(min_n, max_n)` from this module.
+pub trait Extremes<SampleType> {
+  fn min(&self, s1: &SampleType, s2: &SampleType) -> SampleType;
+
+  fn max(&self, s1: &SampleType, s2: &SampleType) -> SampleType;
+}
+impl<SampleType, MinFn, MaxFn> Extremes<SampleType> for (MinFn, MaxFn)
+where
+  MinFn: Fn(&SampleType, &SampleType) -> SampleType,
+  MaxFn: Fn(&SampleType, &SampleType) -> SampleType,
+{
+  #[inline]
+  fn min(&self, s1: &SampleType, s2: &SampleType) -> SampleType {
+    (self.0)(s1, s2)
+  }
+
+  #[inline]
+  fn max(&self, s1: &SampleType, s2: &SampleType) -> SampleType {
+    (self.1)(s1, s2)
+  }
+}
+impl<SampleType, E: Extremes<SampleType>> Extremes<SampleType> for &E {
+  #[inline]
+  fn min(&self, s1: &SampleType, s2: &SampleType) -> SampleType {
+    (**self).min(s1, s2)
+  }
+
+  #[inline]
+  fn max(&self, s1: &SampleType, s2: &SampleType) -> SampleType {
+    (**self).max(s1, s2)
+  }
+}
+
+/// The highest and lowest samples of a signal within `width` samples of
+/// each one, as picked by `extremes`.
+pub struct Envelope<SampleType, E: Extremes<SampleType>, const SIGNAL_SIZE: usize> {
+  pub upper: [SampleType; SIGNAL_SIZE],
+  pub lower: [SampleType; SIGNAL_SIZE],
+  width: usize,
+  extremes: E,
+}
+impl<SampleType: Copy, E: Extremes<SampleType>, const SIGNAL_SIZE: usize>
+  Envelope<SampleType, E, SIGNAL_SIZE>
+{
+  /// The envelope of a signal for a Sakoe-Chiba band `width` samples wide.
+  /// Takes `SIGNAL_SIZE * (2 * width + 1)` comparisons.
+  pub fn new(
+    signal: &[SampleType; SIGNAL_SIZE],
+    width: usize,
+    extremes: E,
+  ) -> Envelope<SampleType, E, SIGNAL_SIZE> {
+    let mut upper: [SampleType; SIGNAL_SIZE] = alloc(false);
+    let mut lower: [SampleType; SIGNAL_SIZE] = alloc(false);
+
+    for t in 0..SIGNAL_SIZE {
+      let first = t.saturating_sub(width);
+      let last = core::cmp::min(t + width, SIGNAL_SIZE - 1);
+      upper[t] = signal[first];
+      lower[t] = signal[first];
+      for sample in signal[first + 1..=last].iter() {
+        upper[t] = extremes.max(&upper[t], sample);
+        lower[t] = extremes.min(&lower[t], sample);
+      }
+    }
+
+    Envelope {
+      upper,
+      lower,
+      width,
+      extremes,
+    }
+  }
+
+  /// The closest sample to `sample` inside of the envelope at `t`.
+  #[inline]
+  fn clamp(&self, sample: &SampleType, t: usize) -> SampleType {
+    let extremes = &self.extremes;
+    extremes.max(&self.lower[t], &extremes.min(sample, &self.upper[t]))
+  }
+}
+
+/// LB_Keogh: every sample of the query has to be matched to a sample of the
+/// candidate inside of the band, which can't be any closer than the
+/// candidate's envelope. Only a lower bound for solves constrained to a
+/// Sakoe-Chiba band no wider than the envelope's (or unconstrained solves
+/// if the envelope covers the whole signal), and for losses that only grow
+/// with the distance between samples.
+#[inline]
+pub fn lb_keogh<SampleType: Copy, C: Cost, E: Extremes<SampleType>, const SIGNAL_SIZE: usize>(
+  query: &[SampleType; SIGNAL_SIZE],
+  candidate_envelope: &Envelope<SampleType, E, SIGNAL_SIZE>,
+  loss_fn: impl Loss<SampleType, C>,
+) -> C {
+  keogh_sum(query, candidate_envelope, &loss_fn)
+}
+
+#[inline]
+fn keogh_sum<SampleType: Copy, C: Cost, E: Extremes<SampleType>, const SIGNAL_SIZE: usize>(
+  query: &[SampleType; SIGNAL_SIZE],
+  candidate_envelope: &Envelope<SampleType, E, SIGNAL_SIZE>,
+  loss_fn: &impl Loss<SampleType, C>,
+) -> C {
+  let mut bound = C::ZERO;
+  for (t, sample) in query.iter().enumerate() {
+    bound = bound.plus(loss_fn.loss(sample, &candidate_envelope.clamp(sample, t)));
+  }
+  bound
+}
+
+/// LB_Improved: LB_Keogh, plus the same bound the other way around between
+/// the candidate and the query after it's been clamped to the candidate's
+/// envelope. Tighter than LB_Keogh for about twice the work.
+///
+/// On top of LB_Keogh's requirements, the step pattern has to match every
+/// sample of the candidate (so not `Asymmetric`), and the loss has to add up
+/// along a line like `dist` does. Euclidean losses between
+/// multi-dimensional samples don't.
+#[inline]
+pub fn lb_improved<SampleType: Copy, C: Cost, E: Extremes<SampleType>, const SIGNAL_SIZE: usize>(
+  query: &[SampleType; SIGNAL_SIZE],
+  candidate: &[SampleType; SIGNAL_SIZE],
+  candidate_envelope: &Envelope<SampleType, E, SIGNAL_SIZE>,
+  loss_fn: impl Loss<SampleType, C>,
+) -> C {
+  let mut projection: [SampleType; SIGNAL_SIZE] = alloc(false);
+  let mut bound = C::ZERO;
+  for (t, sample) in query.iter().enumerate() {
+    projection[t] = candidate_envelope.clamp(sample, t);
+    bound = bound.plus(loss_fn.loss(sample, &projection[t]));
+  }
+
+  let projection_envelope = Envelope::new(
+    &projection,
+    candidate_envelope.width,
+    &candidate_envelope.extremes,
+  );
+  bound.plus(keogh_sum(candidate, &projection_envelope, &loss_fn))
+}
+
+#[inline]
+pub fn min(s1: &f32, s2: &f32) -> f32 {
+  libm::fminf(*s1, *s2)
+}
+
+#[inline]
+pub fn max(s1: &f32, s2: &f32) -> f32 {
+  libm::fmaxf(*s1, *s2)
+}
+
+/// The lowest value of each dimension of two samples
+#[inline]
+pub fn min_n<const N: usize>(s1: &[f32; N], s2: &[f32; N]) -> [f32; N] {
+  let mut lowest: [f32; N] = alloc(false);
+  for i in 0..N {
+    lowest[i] = min(&s1[i], &s2[i]);
+  }
+  lowest
+}
+
+/// The highest value of each dimension of two samples
+#[inline]
+pub fn max_n<const N: usize>(s1: &[f32; N], s2: &[f32; N]) -> [f32; N] {
+  let mut highest: [f32; N] = alloc(false);
+  for i in 0..N {
+    highest[i] = max(&s1[i], &s2[i]);
+  }
+  highest
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::{loss_fns, ExactDtwSolver, GlobalConstraint};
+  use rand::{Rng, SeedableRng};
+
+  #[test]
+  fn envelopes_the_band() {
+    let signal = [0f32, 3f32, 1f32, 4f32, 1f32, 5f32];
+    let envelope = Envelope::new(&signal, 1, (min, max));
+
+    assert_eq!([3f32, 3f32, 4f32, 4f32, 5f32, 5f32], envelope.upper);
+    assert_eq!([0f32, 0f32, 1f32, 1f32, 1f32, 1f32], envelope.lower);
+  }
+
+  #[test]
+  fn envelopes_each_dimension() {
+    let signal = [[0f32, 5f32], [3f32, 1f32], [1f32, 4f32]];
+    let envelope = Envelope::new(&signal, 1, (min_n, max_n));
+
+    assert_eq!([[3f32, 5f32], [3f32, 5f32], [3f32, 4f32]], envelope.upper);
+    assert_eq!([[0f32, 1f32], [0f32, 1f32], [1f32, 1f32]], envelope.lower);
+  }
+
+  #[test]
+  fn bounds_stay_below_distance() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+
+    for _ in 0..50 {
+      let mut query = [0f32; 24];
+      let mut candidate = [0f32; 24];
+      for t in 0..24 {
+        query[t] = rng.gen_range(-5f32, 5f32);
+        candidate[t] = rng.gen_range(-5f32, 5f32);
+      }
+
+      let distance = ExactDtwSolver::<f32, 24, 24, 47>::new(&query, &candidate, loss_fns::dist)
+        .constrain(GlobalConstraint::SakoeChibaBand { width: 3 })
+        .distance();
+      let envelope = Envelope::new(&candidate, 3, (min, max));
+      let keogh = lb_keogh(&query, &envelope, loss_fns::dist);
+      let improved = lb_improved(&query, &candidate, &envelope, loss_fns::dist);
+
+      assert!(lb_kim(&query, &candidate, loss_fns::dist) <= distance);
+      assert!(keogh <= improved);
+      assert!(improved <= distance + 1e-3);
+    }
+  }
+}
@@ -1,27 +1,41 @@
 use crate::alloc::alloc;
+use crate::error::DtwError;
 use crate::{stack_vec::StackVec, StackVecIterator};
 
 //pub type Path<const N: usize> = StackVec<PathPoint, N>;
 
-pub struct Path<const N: usize>(StackVec<PathPoint, N>);
-impl<const N: usize> Path<N> {
-  pub fn empty(zero_mem: bool) -> Path<N> {
+/// A path through the grid, with its losses measured in `C`.
+#[derive(Debug)]
+pub struct Path<const N: usize, C = f32>(StackVec<PathPoint<C>, N>);
+impl<const N: usize, C> Path<N, C> {
+  pub fn empty(zero_mem: bool) -> Path<N, C> {
     Path(StackVec::empty(zero_mem))
   }
 
-  pub fn iter(&self) -> StackVecIterator<PathPoint, N> {
+  pub fn iter(&self) -> StackVecIterator<'_, PathPoint<C>, N> {
     self.0.iter()
   }
 
-  pub fn push(&mut self, item: PathPoint) {
+  pub fn push(&mut self, item: PathPoint<C>) {
     self.0.push(item);
   }
 
-  pub fn warp<SampleType: Copy, const SIGNAL_SIZE: usize>(
+  pub fn try_push(&mut self, item: PathPoint<C>) -> Result<(), DtwError> {
+    self
+      .0
+      .try_push(item)
+      .map_err(|_| DtwError::PathCapacityExceeded { capacity: N })
+  }
+
+  pub fn as_slice(&self) -> &[PathPoint<C>] {
+    self.0.as_slice()
+  }
+
+  pub fn warp<SampleType: Copy, const SIGNAL_Y_SIZE: usize, const SIGNAL_X_SIZE: usize>(
     &self,
-    signal: [SampleType; SIGNAL_SIZE],
-  ) -> [SampleType; SIGNAL_SIZE] {
-    let mut warped: [SampleType; SIGNAL_SIZE] = alloc(false);
+    signal: [SampleType; SIGNAL_Y_SIZE],
+  ) -> [SampleType; SIGNAL_X_SIZE] {
+    let mut warped: [SampleType; SIGNAL_X_SIZE] = alloc(false);
 
     let mut t_signal = 0;
     let mut t_warped = 0;
@@ -37,6 +51,14 @@ impl<const N: usize> Path<N> {
         Move::Vertical => {
           t_signal += 1;
         }
+        Move::Step { y, x } => {
+          // Spread a longer step evenly over the columns it covers
+          for i in 1..x as usize {
+            warped[t_warped + i] = signal[t_signal + y as usize * i / x as usize];
+          }
+          t_signal += y as usize;
+          t_warped += x as usize;
+        }
         _ => {
           break;
         }
@@ -65,6 +87,13 @@ impl<const N: usize> Path<N> {
         Move::Vertical => {
           d += 1f32;
         }
+        Move::Step { y, x } => {
+          for i in 1..x as usize {
+            deviation[t + i] = d + y as f32 * i as f32 / x as f32 - i as f32;
+          }
+          t += x as usize;
+          d += y as f32 - x as f32;
+        }
         _ => {
           break;
         }
@@ -82,10 +111,119 @@ pub enum Move {
   Vertical,
   Horizontal,
   Diagonal,
+  /// A longer move of `y` rows and `x` columns at once, taken by step
+  /// patterns that constrain the slope of the path.
+  Step {
+    y: u8,
+    x: u8,
+  },
+}
+impl Move {
+  /// How many rows and columns the move advances by.
+  #[inline]
+  pub fn offset(&self) -> (usize, usize) {
+    match *self {
+      Move::Stop => (0, 0),
+      Move::Vertical => (1, 0),
+      Move::Horizontal => (0, 1),
+      Move::Diagonal => (1, 1),
+      Move::Step { y, x } => (y as usize, x as usize),
+    }
+  }
 }
 
 #[derive(Debug, Copy, Clone)]
-pub struct PathPoint {
-  pub loss: f32,
+pub struct PathPoint<C = f32> {
+  /// The loss accumulated along the path from this point to its end,
+  /// including this point itself. The first point of a path holds the
+  /// loss of the whole path.
+  pub loss: C,
   pub to_parent: Move,
 }
+
+/// Somewhere to collect the points of a path as it's traced out of the cost
+/// map, with losses measured in `C`.
+pub trait PathBuffer<C> {
+  fn empty_path() -> Self;
+
+  fn push_point(&mut self, point: PathPoint<C>) -> Result<(), DtwError>;
+
+  fn points(&self) -> &[PathPoint<C>];
+}
+
+impl<const N: usize, C> PathBuffer<C> for Path<N, C> {
+  #[inline]
+  fn empty_path() -> Self {
+    Path::empty(false)
+  }
+
+  #[inline]
+  fn push_point(&mut self, point: PathPoint<C>) -> Result<(), DtwError> {
+    self.try_push(point)
+  }
+
+  #[inline]
+  fn points(&self) -> &[PathPoint<C>] {
+    self.as_slice()
+  }
+}
+
+#[cfg(feature = "alloc")]
+impl<C> PathBuffer<C> for alloc_crate::vec::Vec<PathPoint<C>> {
+  #[inline]
+  fn empty_path() -> Self {
+    alloc_crate::vec::Vec::new()
+  }
+
+  #[inline]
+  fn push_point(&mut self, point: PathPoint<C>) -> Result<(), DtwError> {
+    self.push(point);
+    Ok(())
+  }
+
+  #[inline]
+  fn points(&self) -> &[PathPoint<C>] {
+    self
+  }
+}
+
+/// How closely two signals match, along with the path that aligns them. `P`
+/// is the type of the path, like in `Subsequence`.
+#[derive(Debug)]
+pub struct DtwResult<P, C = f32> {
+  /// The total weighted loss along the path, or `Cost::INFINITY` if there's
+  /// no path from one corner of the grid to the other.
+  pub distance: C,
+  /// The distance divided by the total weight the step pattern gives to the
+  /// cells along the path, which makes it the average loss of a matched
+  /// pair of samples. Unlike the distance, it can be compared between pairs
+  /// of signals of different lengths.
+  pub normalized_distance: C,
+  pub path: P,
+}
+
+/// The outcome of a distance-only solve that's allowed to give up early.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistanceOutcome<C = f32> {
+  /// The distance between the signals, which is no greater than the best so
+  /// far
+  Distance(C),
+  /// The distance is certain to be greater than the best so far, so the
+  /// solve gave up without finishing
+  Abandoned,
+}
+
+/// Where a query signal best matches inside of a longer reference signal,
+/// as found by a subsequence solve. `P` is the type of the path, which is a
+/// `Path` from the stack-based solvers or a `Vec<PathPoint>` from
+/// `VecDtwSolver`.
+#[derive(Debug)]
+pub struct Subsequence<P, C = f32> {
+  /// The first sample of the reference that the query was matched to
+  pub start: usize,
+  /// The last sample of the reference that the query was matched to
+  pub end: usize,
+  pub loss: C,
+  /// The path through the grid, starting from `start` on the first row
+  pub path: P,
+}
@@ -0,0 +1,71 @@
+/// The phase penalty of Weighted DTW (WDTW). Every cell's loss is
+/// multiplied by a logistic weight of how far apart its two samples are,
+/// `|y - x|`, so that paths which shift one signal far ahead of the other
+/// cost more than ones that stay near the diagonal:
+///
+/// `w(d) = w_max / (1 + e^(-g * (d - m / 2)))`
+///
+/// where `m` is the length of the longer signal. `g` sets how sharply the
+/// penalty rises around `m / 2`: near 0 every offset weighs about
+/// `w_max / 2`, and large values approach a step from 0 to `w_max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhaseWeight {
+  pub g: f32,
+  pub w_max: f32,
+}
+impl PhaseWeight {
+  /// The equivalent weight on signals that have been downsampled until
+  /// each of their samples spans `scale` samples of the originals. Each
+  /// offset between two downsampled samples spans `scale` offsets too, so
+  /// scaling `g` with it keeps the weight of a cell at that of the cells it
+  /// stands in for.
+  #[inline]
+  pub(crate) fn downsample(&self, scale: usize) -> PhaseWeight {
+    PhaseWeight {
+      g: self.g * scale as f32,
+      w_max: self.w_max,
+    }
+  }
+
+  /// The weight of cell `(y, x)` on a `y_size` by `x_size` grid.
+  #[inline]
+  pub(crate) fn weight(&self, y: usize, x: usize, y_size: usize, x_size: usize) -> f32 {
+    let offset = match y > x {
+      true => y - x,
+      false => x - y,
+    } as f32;
+    let middle = core::cmp::max(y_size, x_size) as f32 / 2f32;
+    self.w_max / (1f32 + libm::expf(-self.g * (offset - middle)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn weighs_offsets_logistically() {
+    let weight = PhaseWeight {
+      g: 1f32,
+      w_max: 2f32,
+    };
+
+    assert_eq!(1f32, weight.weight(2, 6, 8, 8));
+    assert_eq!(1f32, weight.weight(6, 2, 8, 8));
+    assert!(weight.weight(3, 3, 8, 8) < weight.weight(3, 4, 8, 8));
+    assert!(weight.weight(0, 7, 8, 8) < 2f32);
+  }
+
+  #[test]
+  fn keeps_weights_across_levels() {
+    let weight = PhaseWeight {
+      g: 0.25f32,
+      w_max: 1f32,
+    };
+    let downsampled = weight.downsample(4);
+
+    assert_eq!(weight.weight(0, 12, 32, 32), downsampled.weight(0, 3, 8, 8));
+    assert_eq!(weight.weight(4, 4, 32, 32), downsampled.weight(1, 1, 8, 8));
+  }
+}
@@ -0,0 +1,279 @@
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::cost_map::{CostMap, MapCell};
+use crate::error::DtwError;
+use crate::loss_fns::Loss;
+use crate::path::{DistanceOutcome, DtwResult, PathBuffer, PathPoint, Subsequence};
+use crate::phase_weight::PhaseWeight;
+use crate::storage::Storage;
+use crate::window::Row;
+
+/// How many times a level gets widened and solved again by `refine`, at
+/// most. The margin doubles each time, so the last round widens by 8 times
+/// as much as the first.
+const MAX_REFINEMENTS: usize = 4;
+
+/// The loss function, downsample factor, search radius, global constraint,
+/// phase weight and refinement margin to solve a downsample pyramid with.
+pub struct Guide<'g, LossFn> {
+  pub loss_fn: &'g LossFn,
+  pub factor: usize,
+  pub radius: usize,
+  pub constraint: Option<GlobalConstraint>,
+  pub phase_weight: Option<PhaseWeight>,
+  pub refine: Option<usize>,
+}
+
+/// Solves each level of a downsample pyramid in turn, from the smallest
+/// downsample (the first level) up to the original signals (the last). Each
+/// level only evaluates the cells near the path found on the level before
+/// it.
+#[inline]
+pub fn solve<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<P, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let (sig_y, sig_x) = guide_last_level::<_, _, _, _, P>(cost_map, levels, guide)?;
+  refine::<_, _, _, P, _>(
+    cost_map,
+    guide.refine,
+    |cost_map| cost_map.solve(sig_y, sig_x, guide.loss_fn),
+    |_| true,
+  )
+}
+
+/// Like `solve`, but also works out the distance between the signals.
+#[inline]
+pub fn align<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<DtwResult<P, C>, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let (sig_y, sig_x) = guide_last_level::<_, _, _, _, P>(cost_map, levels, guide)?;
+  refine::<_, _, _, P, _>(
+    cost_map,
+    guide.refine,
+    |cost_map| cost_map.align(sig_y, sig_x, guide.loss_fn),
+    |_| true,
+  )
+}
+
+/// The distance between the original signals, found like `solve` but
+/// without tracing the path on the last level. The levels below it still
+/// need their paths to guide the next, and keep them in a `P`.
+#[inline]
+pub fn distance<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<C, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let (sig_y, sig_x) = guide_last_level::<_, _, _, _, P>(cost_map, levels, guide)?;
+  refine::<_, _, _, P, _>(
+    cost_map,
+    guide.refine,
+    |cost_map| cost_map.distance(sig_y, sig_x, guide.loss_fn),
+    |_| true,
+  )
+}
+
+/// Like `distance`, but gives up as soon as it's clear that the distance is
+/// greater than `best_so_far`. The downsamples don't bound the distance
+/// between the original signals, so only the last level can be abandoned.
+/// With a refinement margin, each round is solved against the threshold
+/// too. An abandoned round was never traced, so it doesn't get refined: the
+/// outcome can be `Abandoned` where `distance` would have refined its way
+/// under `best_so_far`.
+#[inline]
+pub fn distance_within<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+  best_so_far: C,
+) -> Result<DistanceOutcome<C>, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let (sig_y, sig_x) = guide_last_level::<_, _, _, _, P>(cost_map, levels, guide)?;
+  refine::<_, _, _, P, _>(
+    cost_map,
+    guide.refine,
+    |cost_map| cost_map.distance_within(sig_y, sig_x, guide.loss_fn, best_so_far),
+    |outcome| match outcome {
+      DistanceOutcome::Distance(_) => true,
+      DistanceOutcome::Abandoned => false,
+    },
+  )
+}
+
+/// Solves every level of the pyramid but the last, then points the cost
+/// map's window at the cells of the last level to evaluate. Returns the
+/// signals of the last level, which the caller solves and refines.
+#[inline]
+fn guide_last_level<'l, 's, SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &'l [(&'s [SampleType], &'s [SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<&'l (&'s [SampleType], &'s [SampleType]), DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let mut last_downsample_path: Option<P> = None;
+  cost_map.use_downsample_factor(guide.factor);
+
+  for (i, (sig_y, sig_x)) in levels.iter().enumerate() {
+    let downsamples = levels.len() - i - 1;
+    let scale = guide.factor.saturating_pow(downsamples as u32);
+    cost_map.guide(
+      last_downsample_path.as_ref().map(|p| p.points()),
+      0,
+      guide.radius,
+      guide.constraint.map(|c| c.downsample(scale)),
+      sig_y.len(),
+      sig_x.len(),
+    )?;
+    cost_map.use_phase_weight(guide.phase_weight.map(|w| w.downsample(scale)));
+
+    if downsamples == 0 {
+      break;
+    }
+
+    // Slope-constrained step patterns can't always make it across a small
+    // downsample. Without a path to follow, the next level gets solved in
+    // full.
+    let path: P = refine::<_, _, _, P, _>(
+      cost_map,
+      guide.refine,
+      |cost_map| cost_map.solve(sig_y, sig_x, guide.loss_fn),
+      |_| true,
+    )?;
+    last_downsample_path = match reaches_end(path.points()) {
+      true => Some(path),
+      false => None,
+    };
+  }
+
+  Ok(
+    levels
+      .last()
+      .expect("a downsample pyramid always has at least one level"),
+  )
+}
+
+/// Solves a level through the cost map's window with `solve`. With a
+/// refinement margin, it then traces the path out of the solved cells,
+/// widens the window wherever the path looks unreliable, and solves the
+/// level again, until nothing is left to widen. Only the unreliable
+/// stretches of the window grow, so the rest of the level keeps its narrow
+/// corridor. A result that `traceable` turns down, like an abandoned
+/// solve, is returned as it is.
+#[inline]
+fn refine<C, Cells, Rows, P, R>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  margin: Option<usize>,
+  mut solve: impl FnMut(&mut CostMap<C, Cells, Rows>) -> Result<R, DtwError>,
+  traceable: impl Fn(&R) -> bool,
+) -> Result<R, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let mut result = solve(cost_map)?;
+
+  if let Some(margin) = margin {
+    for round in 0..MAX_REFINEMENTS {
+      if !traceable(&result) {
+        break;
+      }
+      let path: P = cost_map.trace()?;
+      let margin = core::cmp::max(margin, 1) << round;
+      if !reaches_end(path.points()) || !cost_map.widen_where_unreliable(path.points(), 0, margin) {
+        break;
+      }
+      result = solve(cost_map)?;
+    }
+  }
+
+  Ok(result)
+}
+
+/// Finds where the Y signal best matches inside of the longer X signal,
+/// level by level like `solve`. The match may start and end anywhere along
+/// the X signal. Global constraints and phase weights don't apply to
+/// subsequence matches, since the path isn't expected to follow the
+/// diagonal.
+#[inline]
+pub fn solve_subsequence<SampleType, C, Cells, Rows, P>(
+  cost_map: &mut CostMap<C, Cells, Rows>,
+  levels: &[(&[SampleType], &[SampleType])],
+  guide: &Guide<impl Loss<SampleType, C>>,
+) -> Result<Subsequence<P, C>, DtwError>
+where
+  C: Cost,
+  Cells: Storage<MapCell<C>>,
+  Rows: Storage<Row>,
+  P: PathBuffer<C>,
+{
+  let mut last_downsample: Option<Subsequence<P, C>> = None;
+  cost_map.use_downsample_factor(guide.factor);
+  cost_map.use_phase_weight(None);
+
+  for (i, (sig_y, sig_x)) in levels.iter().enumerate() {
+    cost_map.guide(
+      last_downsample.as_ref().map(|ds| ds.path.points()),
+      last_downsample.as_ref().map_or(0, |ds| ds.start),
+      guide.radius,
+      None,
+      sig_y.len(),
+      sig_x.len(),
+    )?;
+    let found: Subsequence<P, C> = cost_map.solve_subsequence(sig_y, sig_x, guide.loss_fn)?;
+
+    if i == levels.len() - 1 {
+      return Ok(found);
+    }
+
+    last_downsample = match reaches_end(found.path.points()) {
+      true => Some(found),
+      false => None,
+    };
+  }
+
+  unreachable!("a downsample pyramid always has at least one level");
+}
+
+/// Whether a path made it all the way across the grid, rather than running
+/// into a dead end.
+#[inline]
+pub fn reaches_end<C: Cost>(path: &[PathPoint<C>]) -> bool {
+  match path.first() {
+    Some(point) => point.loss != C::INFINITY,
+    None => false,
+  }
+}
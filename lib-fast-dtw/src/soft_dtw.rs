@@ -0,0 +1,402 @@
+//! Soft-DTW (Cuturi and Blondel, 2017): a differentiable stand-in for the
+//! DTW distance, for training models with an alignment loss. Rather than
+//! taking the cheapest of the moves into each cell, it takes their soft
+//! minimum, which blends every path between the signals weighted by how
+//! cheap it is. The smoothing parameter `gamma` sets how much: as it
+//! approaches 0 the value approaches the DTW distance under
+//! `StepPattern::Symmetric1`.
+
+use crate::error::{or_panic, DtwError};
+use crate::loss_fns::{Loss, LossGradient};
+use crate::path::PathPoint;
+use crate::storage::Storage;
+use crate::window::{Row, Window};
+
+/// One cell of the grid: the loss between its two samples, the soft minimum
+/// cost of reaching it from the first cell, and how much it contributes to
+/// the value, which is its share of the gradient.
+#[derive(Clone, Copy, Debug)]
+pub struct SoftCell {
+  loss: f32,
+  cost: f32,
+  alignment: f32,
+}
+impl SoftCell {
+  pub const EMPTY: SoftCell = SoftCell {
+    loss: 0f32,
+    cost: f32::INFINITY,
+    alignment: 0f32,
+  };
+}
+
+/// Works out the Soft-DTW value between two signals, and its gradient with
+/// respect to each of their samples.
+///
+/// Every cell of the grid is evaluated by default. `corridor` restricts it
+/// to the cells near a path found by a solver, which smooths over only the
+/// paths close to it and takes far less work on long signals. The cells are
+/// kept in `Cells` like those of a `DtwSolver`, and a corridor only needs
+/// room for the cells inside of it.
+pub struct SoftDtw<
+  'a,
+  SampleType,
+  const SIGNAL_Y_SIZE: usize,
+  const SIGNAL_X_SIZE: usize,
+  LossFn: Loss<SampleType> = fn(&SampleType, &SampleType) -> f32,
+  Cells: Storage<SoftCell> = [[SoftCell; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+> {
+  sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+  sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+  loss_fn: LossFn,
+  gamma: f32,
+  cells: Cells,
+  window: Window<[Row; SIGNAL_Y_SIZE]>,
+}
+impl<'a, SampleType, const SIGNAL_Y_SIZE: usize, const SIGNAL_X_SIZE: usize, LossFn>
+  SoftDtw<
+    'a,
+    SampleType,
+    SIGNAL_Y_SIZE,
+    SIGNAL_X_SIZE,
+    LossFn,
+    [[SoftCell; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+  >
+where
+  LossFn: Loss<SampleType>,
+{
+  /// Creates a Soft-DTW between two signals with smoothing `gamma`, which
+  /// has to be greater than 0.
+  pub fn new(
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    loss_fn: LossFn,
+    gamma: f32,
+  ) -> Self {
+    SoftDtw::with_cells(
+      sig_y,
+      sig_x,
+      loss_fn,
+      gamma,
+      [[SoftCell::EMPTY; SIGNAL_X_SIZE]; SIGNAL_Y_SIZE],
+    )
+  }
+}
+impl<'a, SampleType, const SIGNAL_Y_SIZE: usize, const SIGNAL_X_SIZE: usize, LossFn, Cells>
+  SoftDtw<'a, SampleType, SIGNAL_Y_SIZE, SIGNAL_X_SIZE, LossFn, Cells>
+where
+  LossFn: Loss<SampleType>,
+  Cells: Storage<SoftCell>,
+{
+  /// Fails the build if either signal has no samples. It's only evaluated
+  /// once it's used, so `with_cells` refers to it.
+  const VALID_PARAMS: () = assert!(
+    SIGNAL_Y_SIZE > 0 && SIGNAL_X_SIZE > 0,
+    "both signals need at least one sample"
+  );
+
+  /// Creates a Soft-DTW that keeps its cells in `cells`, which only has to
+  /// hold the cells of the corridor it's restricted to.
+  pub fn with_cells(
+    sig_y: &'a [SampleType; SIGNAL_Y_SIZE],
+    sig_x: &'a [SampleType; SIGNAL_X_SIZE],
+    loss_fn: LossFn,
+    gamma: f32,
+    cells: Cells,
+  ) -> Self {
+    #[allow(clippy::let_unit_value)]
+    let () = Self::VALID_PARAMS;
+
+    let mut window = Window::new([Row::EMPTY; SIGNAL_Y_SIZE]);
+    window.full(SIGNAL_Y_SIZE, SIGNAL_X_SIZE);
+    SoftDtw {
+      sig_y,
+      sig_x,
+      loss_fn,
+      gamma,
+      cells,
+      window,
+    }
+  }
+
+  /// Only smooths over the paths that stay within `radius` cells of `path`,
+  /// like one found by `DtwSolver::solve`. Fails if the path doesn't lead
+  /// from the first cell of the grid to the last.
+  pub fn corridor<C>(
+    &mut self,
+    path: &[PathPoint<C>],
+    radius: usize,
+  ) -> Result<&mut Self, DtwError> {
+    self.window.trace(path, 0, SIGNAL_Y_SIZE, SIGNAL_X_SIZE)?;
+    self.window.expand(radius, SIGNAL_Y_SIZE, SIGNAL_X_SIZE);
+    match self.window.contains(SIGNAL_Y_SIZE - 1, SIGNAL_X_SIZE - 1) {
+      true => Ok(self),
+      false => Err(DtwError::InvalidGuidePath),
+    }
+  }
+
+  /// Smooths over every path between the signals again, after `corridor`.
+  pub fn exact(&mut self) -> &mut Self {
+    self.window.full(SIGNAL_Y_SIZE, SIGNAL_X_SIZE);
+    self
+  }
+
+  /// The Soft-DTW value: the soft minimum of the total loss of every path
+  /// through the cells evaluated.
+  #[inline]
+  pub fn value(&mut self) -> f32 {
+    or_panic(self.try_value())
+  }
+
+  /// Like `value`, but returns an error rather than panicking if the cells
+  /// don't fit in their storage.
+  #[inline]
+  pub fn try_value(&mut self) -> Result<f32, DtwError> {
+    self.resize()?;
+    self.calc_costs();
+    Ok(self.cell(SIGNAL_Y_SIZE - 1, SIGNAL_X_SIZE - 1).cost)
+  }
+
+  /// Works out the value like `value`, and adds its gradient with respect
+  /// to each sample of `sig_y` and `sig_x` onto `grad_y` and `grad_x`.
+  /// Start them at zero for the gradient alone, or keep adding onto them to
+  /// sum the gradients of a batch. Returns the value.
+  #[inline]
+  pub fn gradient(
+    &mut self,
+    grad_y: &mut [SampleType; SIGNAL_Y_SIZE],
+    grad_x: &mut [SampleType; SIGNAL_X_SIZE],
+  ) -> f32
+  where
+    LossFn: LossGradient<SampleType>,
+  {
+    or_panic(self.try_gradient(grad_y, grad_x))
+  }
+
+  /// Like `gradient`, but returns an error rather than panicking if the
+  /// cells don't fit in their storage.
+  #[inline]
+  pub fn try_gradient(
+    &mut self,
+    grad_y: &mut [SampleType; SIGNAL_Y_SIZE],
+    grad_x: &mut [SampleType; SIGNAL_X_SIZE],
+  ) -> Result<f32, DtwError>
+  where
+    LossFn: LossGradient<SampleType>,
+  {
+    let value = self.try_value()?;
+    self.calc_alignments();
+
+    for (y, grad_y) in grad_y.iter_mut().enumerate() {
+      let (min_x, max_x) = self.window.row(y);
+      for (x, grad_x) in grad_x.iter_mut().enumerate().take(max_x + 1).skip(min_x) {
+        let alignment = self.cell(y, x).alignment;
+        if alignment > 0f32 {
+          self
+            .loss_fn
+            .add_gradient(&self.sig_y[y], &self.sig_x[x], alignment, grad_y, grad_x);
+        }
+      }
+    }
+
+    Ok(value)
+  }
+
+  #[inline]
+  fn resize(&mut self) -> Result<(), DtwError> {
+    let len = self.window.pack(SIGNAL_Y_SIZE);
+    self.cells.make_room(len, SoftCell::EMPTY);
+    match len <= self.cells.items().len() {
+      true => Ok(()),
+      false => Err(DtwError::CellCapacityExceeded {
+        needed: len,
+        capacity: self.cells.items().len(),
+      }),
+    }
+  }
+
+  #[inline]
+  fn cell(&self, y: usize, x: usize) -> &SoftCell {
+    &self.cells.items()[self.window.index(y, x)]
+  }
+
+  #[inline]
+  fn cell_mut(&mut self, y: usize, x: usize) -> &mut SoftCell {
+    let index = self.window.index(y, x);
+    &mut self.cells.items_mut()[index]
+  }
+
+  /// The soft minimum cost of reaching cell `(y, x)`, or infinity if it's
+  /// outside of the window.
+  #[inline]
+  fn cost(&self, y: usize, x: usize) -> f32 {
+    match self.window.contains(y, x) {
+      true => self.cell(y, x).cost,
+      false => f32::INFINITY,
+    }
+  }
+
+  /// Fills in the loss and cost of every cell in the window, from the first
+  /// cell forwards. Each cell is reached from the cells below it, to its
+  /// left, and diagonally below and to its left.
+  #[inline]
+  fn calc_costs(&mut self) {
+    for y in 0..SIGNAL_Y_SIZE {
+      let (min_x, max_x) = self.window.row(y);
+      for x in min_x..=max_x {
+        let loss = self.loss_fn.loss(&self.sig_y[y], &self.sig_x[x]);
+        let before = match (y, x) {
+          (0, 0) => 0f32,
+          (0, _) => self.cost(y, x - 1),
+          (_, 0) => self.cost(y - 1, x),
+          _ => soft_min(
+            self.gamma,
+            self.cost(y - 1, x - 1),
+            self.cost(y - 1, x),
+            self.cost(y, x - 1),
+          ),
+        };
+
+        let cell = self.cell_mut(y, x);
+        cell.loss = loss;
+        cell.cost = loss + before;
+      }
+    }
+  }
+
+  /// Fills in the alignment of every cell in the window, from the last cell
+  /// backwards. A cell's alignment is the sum of its successors', each
+  /// scaled by the share of the successor's soft minimum the cell makes up.
+  #[inline]
+  fn calc_alignments(&mut self) {
+    for y in (0..SIGNAL_Y_SIZE).rev() {
+      let (min_x, max_x) = self.window.row(y);
+      for x in (min_x..=max_x).rev() {
+        let cost = self.cell(y, x).cost;
+        let alignment = match (y + 1 == SIGNAL_Y_SIZE, x + 1 == SIGNAL_X_SIZE) {
+          (true, true) => 1f32,
+          _ if cost == f32::INFINITY => 0f32,
+          _ => {
+            self.share(cost, y + 1, x) + self.share(cost, y, x + 1) + self.share(cost, y + 1, x + 1)
+          }
+        };
+        self.cell_mut(y, x).alignment = alignment;
+      }
+    }
+  }
+
+  /// The part of the alignment of successor cell `(y, x)` owed to a cell
+  /// that costs `cost` to reach.
+  #[inline]
+  fn share(&self, cost: f32, y: usize, x: usize) -> f32 {
+    match y < SIGNAL_Y_SIZE && x < SIGNAL_X_SIZE && self.window.contains(y, x) {
+      true => {
+        let successor = self.cell(y, x);
+        successor.alignment * libm::expf((successor.cost - successor.loss - cost) / self.gamma)
+      }
+      false => 0f32,
+    }
+  }
+}
+
+/// `-gamma * ln(e^(-a / gamma) + e^(-b / gamma) + e^(-c / gamma))`, worked
+/// out relative to the smallest of the three so that the exponents can't
+/// overflow.
+#[inline]
+fn soft_min(gamma: f32, a: f32, b: f32, c: f32) -> f32 {
+  let min = libm::fminf(a, libm::fminf(b, c));
+  if min == f32::INFINITY {
+    return f32::INFINITY;
+  }
+
+  let sum =
+    libm::expf((min - a) / gamma) + libm::expf((min - b) / gamma) + libm::expf((min - c) / gamma);
+  min - gamma * libm::logf(sum)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::loss_fns::SquaredError;
+  use crate::test_signals::{SHORT_X, SHORT_Y};
+  use crate::{downsample_fns, DtwSolver, ExactDtwSolver, StepPattern};
+
+  #[test]
+  fn approaches_dtw_as_gamma_shrinks() {
+    let (sig_y, sig_x) = (SHORT_Y, SHORT_X);
+
+    let dtw = ExactDtwSolver::<f32, 10, 8, 17, _, _, _>::new(&sig_y, &sig_x, SquaredError)
+      .step_pattern(StepPattern::Symmetric1)
+      .distance();
+    let sharp = SoftDtw::<f32, 10, 8, _>::new(&sig_y, &sig_x, SquaredError, 0.01f32).value();
+    let smooth = SoftDtw::<f32, 10, 8, _>::new(&sig_y, &sig_x, SquaredError, 1f32).value();
+
+    assert!(libm::fabsf(dtw - sharp) < 0.1f32);
+    assert!(smooth < sharp);
+  }
+
+  #[test]
+  fn matches_finite_differences() {
+    let sig_y = [0f32, 1f32, 5f32, 3f32, 2f32, 1f32];
+    let sig_x = [3f32, 2f32, 3f32, 1f32, 0f32];
+    let value = |sig_y: &[f32; 6], sig_x: &[f32; 5]| {
+      SoftDtw::<f32, 6, 5, _>::new(sig_y, sig_x, SquaredError, 1f32).value()
+    };
+
+    let (mut grad_y, mut grad_x) = ([0f32; 6], [0f32; 5]);
+    SoftDtw::<f32, 6, 5, _>::new(&sig_y, &sig_x, SquaredError, 1f32)
+      .gradient(&mut grad_y, &mut grad_x);
+
+    let step = 0.01f32;
+    for t in 0..6 {
+      let (mut above, mut below) = (sig_y, sig_y);
+      above[t] += step;
+      below[t] -= step;
+      let slope = (value(&above, &sig_x) - value(&below, &sig_x)) / (2f32 * step);
+      assert!(libm::fabsf(slope - grad_y[t]) < 0.05f32);
+    }
+    for t in 0..5 {
+      let (mut above, mut below) = (sig_x, sig_x);
+      above[t] += step;
+      below[t] -= step;
+      let slope = (value(&sig_y, &above) - value(&sig_y, &below)) / (2f32 * step);
+      assert!(libm::fabsf(slope - grad_x[t]) < 0.05f32);
+    }
+  }
+
+  #[test]
+  fn smooths_over_corridor_of_solved_path() {
+    let mut sig_y = [0f32; 32];
+    let mut sig_x = [0f32; 32];
+    for t in 0..32 {
+      sig_y[t] = (t % 9) as f32;
+      sig_x[t] = ((t + 2) % 9) as f32;
+    }
+    let path = DtwSolver::<f32, 32, 32, 63, _, _, _, _>::new(
+      &sig_y,
+      &sig_x,
+      downsample_fns::mean_u8,
+      SquaredError,
+    )
+    .step_pattern(StepPattern::Symmetric1)
+    .search_radius(1)
+    .solve();
+
+    let mut full = SoftDtw::<f32, 32, 32, _>::new(&sig_y, &sig_x, SquaredError, 1f32);
+    let exact = full.value();
+    let wide = full.corridor(path.as_slice(), 32).unwrap().value();
+    let mut narrow = SoftDtw::<f32, 32, 32, _, _>::with_cells(
+      &sig_y,
+      &sig_x,
+      SquaredError,
+      1f32,
+      [SoftCell::EMPTY; 32 * 12],
+    );
+
+    // The full grid doesn't fit in the narrow storage, but the corridor does
+    assert!(narrow.try_value().is_err());
+    let near_path = narrow.corridor(path.as_slice(), 2).unwrap().value();
+    assert_eq!(exact, wide);
+    assert!(near_path >= exact);
+  }
+}
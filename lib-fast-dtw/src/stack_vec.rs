@@ -1,4 +1,4 @@
-use crate::alloc::alloc;
+use crate::alloc::{alloc, zeroed};
 
 #[derive(Debug)]
 pub struct StackVec<T, const N: usize> {
@@ -14,6 +14,16 @@ impl<T, const N: usize> StackVec<T, N> {
     }
   }
 
+  /// An empty `StackVec` with zeroed storage, which unlike `empty` can be
+  /// built at compile time.
+  #[inline]
+  pub const fn zeroed() -> StackVec<T, N> {
+    StackVec {
+      items: zeroed(),
+      len: 0,
+    }
+  }
+
   #[inline]
   pub fn push(&mut self, item: T) {
     if self.len >= N {
@@ -24,14 +34,49 @@ impl<T, const N: usize> StackVec<T, N> {
     self.len += 1;
   }
 
+  /// Pushes an item if there's room for it, or hands it back if the
+  /// `StackVec` is full.
+  #[inline]
+  pub fn try_push(&mut self, item: T) -> Result<(), T> {
+    if self.len >= N {
+      return Err(item);
+    }
+
+    self.items[self.len] = item;
+    self.len += 1;
+    Ok(())
+  }
+
   #[inline]
   pub fn len(&self) -> usize {
     self.len
   }
 
   #[inline]
-  pub fn iter(&self) -> StackVecIterator<T, N> {
-    StackVecIterator::new(&self)
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  #[inline]
+  pub fn as_slice(&self) -> &[T] {
+    &self.items[..self.len]
+  }
+
+  /// Every slot, including the ones past the end, for filling in place
+  /// before `set_len` takes them in.
+  #[inline]
+  pub(crate) fn slots_mut(&mut self) -> &mut [T; N] {
+    &mut self.items
+  }
+
+  #[inline]
+  pub(crate) fn set_len(&mut self, len: usize) {
+    self.len = core::cmp::min(len, N);
+  }
+
+  #[inline]
+  pub fn iter(&self) -> StackVecIterator<'_, T, N> {
+    StackVecIterator::new(self)
   }
 }
 impl<T, const N: usize> core::ops::Index<usize> for StackVec<T, N> {
@@ -69,7 +114,7 @@ pub struct StackVecIterator<'a, T, const N: usize> {
 }
 impl<'a, T, const N: usize> StackVecIterator<'a, T, N> {
   #[inline]
-  pub fn new(stack_vec: &'a StackVec<T, N>) -> StackVecIterator<T, N> {
+  pub fn new(stack_vec: &'a StackVec<T, N>) -> StackVecIterator<'a, T, N> {
     StackVecIterator { stack_vec, pos: 0 }
   }
 }
@@ -141,6 +186,15 @@ mod tests {
     vec.push(7);
   }
 
+  #[test]
+  fn hands_back_items_when_full() {
+    let mut vec = StackVec::<usize, 2>::empty(false);
+    assert_eq!(Ok(()), vec.try_push(1));
+    assert_eq!(Ok(()), vec.try_push(2));
+    assert_eq!(Err(3), vec.try_push(3));
+    assert_eq!(2, vec.len());
+  }
+
   #[test]
   fn is_indexable() {
     let mut vec = StackVec::<u8, 5>::empty(false);
@@ -154,12 +208,14 @@ mod tests {
 
   #[test]
   #[should_panic(expected = "index out of bounds: the len is 0 but the index is 0")]
+  #[allow(clippy::unnecessary_operation)]
   fn panics_on_zero_index_when_empty() {
     StackVec::<u8, 5>::empty(false)[0];
   }
 
   #[test]
   #[should_panic(expected = "index out of bounds: the len is 3 but the index is 3")]
+  #[allow(clippy::no_effect)]
   fn panics_on_index_above_len() {
     let mut vec = StackVec::<u8, 5>::empty(false);
     vec.push(2);
@@ -0,0 +1,161 @@
+use crate::path::{Move, PathPoint};
+
+/// The moves a path may take between cells, and how heavily each move
+/// weighs the losses of the cells it passes through.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StepPattern {
+  /// Vertical, horizontal and diagonal moves, all with a weight of 1.
+  /// Favours diagonal moves, since they cover more ground for the same
+  /// weight.
+  Symmetric1,
+  /// Vertical and horizontal moves with a weight of 1, and diagonal moves
+  /// with a weight of 2. Every path between two signals accumulates the same
+  /// total weight of `N + M - 1`, since the first cell only counts once, so
+  /// losses can be compared across signal lengths by dividing by it.
+  #[default]
+  Symmetric2,
+  /// Every move advances exactly one sample along Y, and zero, one or two
+  /// samples along X. Each sample of Y is matched exactly once, which makes
+  /// it the natural choice for matching a query against a reference.
+  Asymmetric,
+  /// Sakoe and Chiba's symmetric pattern with no slope constraint. The same
+  /// as `Symmetric2`.
+  SymmetricP0,
+  /// Sakoe and Chiba's symmetric pattern with a slope constraint of 1. The
+  /// path must take at least one diagonal move for every vertical or
+  /// horizontal one.
+  SymmetricP1,
+  /// Sakoe and Chiba's symmetric pattern with a slope constraint of 2. The
+  /// path must take at least two diagonal moves for every vertical or
+  /// horizontal one.
+  SymmetricP2,
+}
+impl StepPattern {
+  /// The steps the pattern allows. Ties between steps of equal loss go to
+  /// the earliest one.
+  #[inline]
+  pub(crate) fn steps(&self) -> &'static [Step] {
+    match *self {
+      StepPattern::Symmetric1 => &SYMMETRIC_1,
+      StepPattern::Symmetric2 | StepPattern::SymmetricP0 => &SYMMETRIC_2,
+      StepPattern::Asymmetric => &ASYMMETRIC,
+      StepPattern::SymmetricP1 => &SYMMETRIC_P1,
+      StepPattern::SymmetricP2 => &SYMMETRIC_P2,
+    }
+  }
+
+  /// The most rows any one step advances by.
+  #[inline]
+  pub(crate) fn max_rows(&self) -> usize {
+    self
+      .steps()
+      .iter()
+      .map(|step| step.to_parent.offset().0)
+      .max()
+      .unwrap_or(1)
+  }
+
+  /// The total weight the pattern gives to the losses of the cells along a
+  /// path. The first cell always has a weight of 1.
+  #[inline]
+  pub(crate) fn path_weight<C>(&self, path: &[PathPoint<C>]) -> u32 {
+    let mut weight = 1;
+    for point in path.iter() {
+      let step = self
+        .steps()
+        .iter()
+        .find(|step| step.to_parent == point.to_parent);
+      match step {
+        Some(step) => weight += step.weights.iter().map(|w| w.2).sum::<u32>(),
+        None => break,
+      };
+    }
+    weight
+  }
+}
+
+/// One move allowed by a step pattern.
+#[derive(Debug)]
+pub(crate) struct Step {
+  pub to_parent: Move,
+  /// The cells whose losses the step adds to the path, as
+  /// `(y offset, x offset, weight)` from the cell the step is taken from.
+  /// The last one is always the cell the step lands on. Weights are whole
+  /// numbers, so they can be applied to any `Cost`.
+  pub weights: &'static [(usize, usize, u32)],
+}
+
+const SYMMETRIC_1: [Step; 3] = [
+  Step {
+    to_parent: Move::Diagonal,
+    weights: &[(1, 1, 1)],
+  },
+  Step {
+    to_parent: Move::Vertical,
+    weights: &[(1, 0, 1)],
+  },
+  Step {
+    to_parent: Move::Horizontal,
+    weights: &[(0, 1, 1)],
+  },
+];
+
+const SYMMETRIC_2: [Step; 3] = [
+  Step {
+    to_parent: Move::Diagonal,
+    weights: &[(1, 1, 2)],
+  },
+  Step {
+    to_parent: Move::Vertical,
+    weights: &[(1, 0, 1)],
+  },
+  Step {
+    to_parent: Move::Horizontal,
+    weights: &[(0, 1, 1)],
+  },
+];
+
+const ASYMMETRIC: [Step; 3] = [
+  Step {
+    to_parent: Move::Diagonal,
+    weights: &[(1, 1, 1)],
+  },
+  Step {
+    to_parent: Move::Vertical,
+    weights: &[(1, 0, 1)],
+  },
+  Step {
+    to_parent: Move::Step { y: 1, x: 2 },
+    weights: &[(1, 2, 1)],
+  },
+];
+
+const SYMMETRIC_P1: [Step; 3] = [
+  Step {
+    to_parent: Move::Diagonal,
+    weights: &[(1, 1, 2)],
+  },
+  Step {
+    to_parent: Move::Step { y: 2, x: 1 },
+    weights: &[(1, 1, 2), (2, 1, 1)],
+  },
+  Step {
+    to_parent: Move::Step { y: 1, x: 2 },
+    weights: &[(1, 1, 2), (1, 2, 1)],
+  },
+];
+
+const SYMMETRIC_P2: [Step; 3] = [
+  Step {
+    to_parent: Move::Diagonal,
+    weights: &[(1, 1, 2)],
+  },
+  Step {
+    to_parent: Move::Step { y: 3, x: 2 },
+    weights: &[(1, 1, 2), (2, 2, 2), (3, 2, 1)],
+  },
+  Step {
+    to_parent: Move::Step { y: 2, x: 3 },
+    weights: &[(1, 1, 2), (2, 2, 2), (2, 3, 1)],
+  },
+];
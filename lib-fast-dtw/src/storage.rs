@@ -0,0 +1,72 @@
+/// Backing storage for the cells and rows of a cost map. Arrays live on the
+/// stack and can't grow, so they have to be sized for the largest window up
+/// front. A borrowed slice works the same way, and lets the cells live in a
+/// `static` buffer instead. With the `alloc` feature, a `Vec` grows to fit
+/// whatever it's asked to hold.
+pub trait Storage<T: Copy> {
+  fn items(&self) -> &[T];
+
+  fn items_mut(&mut self) -> &mut [T];
+
+  /// Makes room for at least `len` items, filling any new ones with `fill`.
+  /// Storage that can't grow is left alone, so it's up to the caller to stay
+  /// within its capacity.
+  #[inline]
+  fn make_room(&mut self, _len: usize, _fill: T) {}
+}
+
+impl<T: Copy, const N: usize> Storage<T> for [T; N] {
+  #[inline]
+  fn items(&self) -> &[T] {
+    self
+  }
+
+  #[inline]
+  fn items_mut(&mut self) -> &mut [T] {
+    self
+  }
+}
+
+impl<T: Copy, const X_SIZE: usize, const Y_SIZE: usize> Storage<T> for [[T; X_SIZE]; Y_SIZE] {
+  #[inline]
+  fn items(&self) -> &[T] {
+    self.as_flattened()
+  }
+
+  #[inline]
+  fn items_mut(&mut self) -> &mut [T] {
+    self.as_flattened_mut()
+  }
+}
+
+impl<T: Copy> Storage<T> for &mut [T] {
+  #[inline]
+  fn items(&self) -> &[T] {
+    self
+  }
+
+  #[inline]
+  fn items_mut(&mut self) -> &mut [T] {
+    self
+  }
+}
+
+#[cfg(feature = "alloc")]
+impl<T: Copy> Storage<T> for alloc_crate::vec::Vec<T> {
+  #[inline]
+  fn items(&self) -> &[T] {
+    self
+  }
+
+  #[inline]
+  fn items_mut(&mut self) -> &mut [T] {
+    self
+  }
+
+  #[inline]
+  fn make_room(&mut self, len: usize, fill: T) {
+    if self.len() < len {
+      self.resize(len, fill);
+    }
+  }
+}
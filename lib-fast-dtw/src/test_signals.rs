@@ -0,0 +1,39 @@
+//! Signals shared by the tests of more than one module.
+
+/// A pair of short signals of different lengths, whose alignment takes
+/// vertical, horizontal and diagonal moves.
+pub const SHORT_Y: [f32; 10] = [0f32, 1f32, 5f32, 3f32, 2f32, 1f32, 0f32, 4f32, 2f32, 3f32];
+pub const SHORT_X: [f32; 8] = [3f32, 2f32, 3f32, 1f32, 0f32, 2f32, 0f32, 1f32];
+
+/// The short signals carried on to 16 samples each, long enough to be
+/// downsampled twice.
+pub const LONG_Y: [f32; 16] = [
+  0f32, 1f32, 5f32, 3f32, 2f32, 1f32, 0f32, 4f32, 2f32, 3f32, 2f32, 1f32, 0f32, 6f32, 1f32, 2f32,
+];
+pub const LONG_X: [f32; 16] = [
+  3f32, 2f32, 3f32, 1f32, 0f32, 2f32, 0f32, 1f32, 5f32, 1f32, 3f32, 0f32, 2f32, 2f32, 4f32, 0f32,
+];
+
+/// The same spike, past the middle of one signal and at the start of the
+/// other, so the best path strays far from the diagonal.
+pub const SPIKE_Y: [f32; 16] = [
+  0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 1f32, 5f32, 1f32, 0f32, 0f32, 0f32, 0f32, 0f32,
+];
+pub const SPIKE_X: [f32; 16] = [
+  1f32, 5f32, 1f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32, 0f32,
+];
+
+/// A shifted sawtooth with a bump that the downsamples smooth over, which
+/// leads a radius 1 guided solve well away from the optimal path.
+pub fn astray() -> ([f32; 64], [f32; 64]) {
+  let mut sig_y = [0f32; 64];
+  let mut sig_x = [0f32; 64];
+  for t in 0..64 {
+    sig_y[t] = ((t * 7 + 8) % 13) as f32;
+    sig_x[t] = (((t + 5) * 7 + 8) % 13) as f32;
+  }
+  for sample in sig_x[24..30].iter_mut() {
+    *sample += 9f32;
+  }
+  (sig_y, sig_x)
+}
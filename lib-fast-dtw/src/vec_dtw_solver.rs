@@ -0,0 +1,443 @@
+use crate::alloc_crate::vec::Vec;
+use crate::constraint::GlobalConstraint;
+use crate::cost::Cost;
+use crate::cost_map::{CostMap, MapCell};
+use crate::derivative::{derive, Derivative};
+use crate::downsample::shrink;
+use crate::downsample_fns::Downsampler;
+use crate::error::{or_panic, DtwError};
+use crate::evaluation::{self, Evaluation};
+use crate::loss_fns::Loss;
+use crate::path::{DistanceOutcome, DtwResult, PathPoint, Subsequence};
+use crate::phase_weight::PhaseWeight;
+use crate::pyramid::{self, Guide};
+use crate::step_pattern::StepPattern;
+use crate::window::Row;
+
+/// The same solver as `DtwSolver`, but with its maps and downsamples kept on
+/// the heap. It takes signals of any length, known only at runtime, and
+/// isn't limited by the size of the stack. The maps are kept between solves
+/// and only grow when they're asked to hold a bigger grid, so reusing a
+/// solver for signals of similar lengths doesn't allocate them again.
+///
+/// Like `DtwSolver`, it takes anything that implements `Loss` and
+/// `Downsampler` for its loss and downsample functions.
+///
+/// Only available with the `alloc` feature.
+pub struct VecDtwSolver<
+  'a,
+  SampleType,
+  C: Cost = f32,
+  LossFn: Loss<SampleType, C> = fn(&SampleType, &SampleType) -> C,
+  DownsampleFn: Downsampler<SampleType> = fn(&[SampleType]) -> SampleType,
+> {
+  sig_y: &'a [SampleType],
+  sig_x: &'a [SampleType],
+  downsample_fn: DownsampleFn,
+  loss_fn: LossFn,
+  derive_fn: Option<DerivedFn<SampleType>>,
+  cost_map: CostMap<C, Vec<MapCell<C>>, Vec<Row>>,
+  downsample_limit: Option<usize>,
+  downsample_factor: usize,
+  radius: usize,
+  constraint: Option<GlobalConstraint>,
+  phase_weight: Option<PhaseWeight>,
+  refine: Option<usize>,
+}
+impl<'a, SampleType, C: Cost, LossFn: Loss<SampleType, C>, DownsampleFn: Downsampler<SampleType>>
+  VecDtwSolver<'a, SampleType, C, LossFn, DownsampleFn>
+{
+  pub fn new(
+    sig_y: &'a [SampleType],
+    sig_x: &'a [SampleType],
+    downsample_fn: DownsampleFn,
+    loss_fn: LossFn,
+  ) -> Self {
+    VecDtwSolver {
+      sig_y,
+      sig_x,
+      downsample_fn,
+      loss_fn,
+      derive_fn: None,
+      cost_map: CostMap::new(Vec::new(), Vec::new()),
+      downsample_limit: None,
+      downsample_factor: 2,
+      radius: 0,
+      constraint: None,
+      phase_weight: None,
+      refine: None,
+    }
+  }
+
+  /// Switches to a new pair of signals, keeping the maps allocated for the
+  /// last ones along with the rest of the settings.
+  pub fn signals(&mut self, sig_y: &'a [SampleType], sig_x: &'a [SampleType]) -> &mut Self {
+    self.sig_y = sig_y;
+    self.sig_x = sig_x;
+    self
+  }
+
+  pub fn limit_downsamples(&mut self, max_downsamples: usize) -> &mut Self {
+    self.downsample_limit = Some(max_downsamples);
+    self
+  }
+
+  /// Shrinks the signals by `factor` at each level of the downsample
+  /// pyramid, rather than halving them. A bigger factor means fewer levels
+  /// to solve, but a coarser path to guide each one with, so it takes a
+  /// wider search radius to stay as accurate. Factors below 2 are treated
+  /// as 2.
+  pub fn downsample_factor(&mut self, factor: usize) -> &mut Self {
+    self.downsample_factor = core::cmp::max(factor, 2);
+    self
+  }
+
+  /// Widens the corridor of cells evaluated around each projected downsample
+  /// path by `radius` cells on each side. A larger radius trades speed for a
+  /// better chance of finding the optimal path.
+  pub fn search_radius(&mut self, radius: usize) -> &mut Self {
+    self.radius = radius;
+    self
+  }
+
+  /// Re-solves the stretches of each level where the guided path looks
+  /// unreliable with a window widened by `margin` cells. See
+  /// `DtwSolver::refine`. The maps grow to hold the widened windows, so
+  /// refining can't run out of room, but the first refined solve of a size
+  /// may have to allocate.
+  pub fn refine(&mut self, margin: usize) -> &mut Self {
+    self.refine = Some(margin);
+    self
+  }
+
+  /// Restricts the path to the cells allowed by a global constraint, at
+  /// every level of the downsample pyramid.
+  pub fn constrain(&mut self, constraint: GlobalConstraint) -> &mut Self {
+    self.constraint = Some(constraint);
+    self
+  }
+
+  /// Penalizes phase shifts with a logistic weight on every cell's loss
+  /// (weighted DTW). Each level of the downsample pyramid is weighed with
+  /// the offsets of the original signals its cells stand in for.
+  pub fn phase_weight(&mut self, phase_weight: PhaseWeight) -> &mut Self {
+    self.phase_weight = Some(phase_weight);
+    self
+  }
+
+  /// Chooses the moves the path may take and how they're weighted. Defaults
+  /// to `StepPattern::Symmetric2`.
+  pub fn step_pattern(&mut self, step_pattern: StepPattern) -> &mut Self {
+    self.cost_map.use_step_pattern(step_pattern);
+    self
+  }
+
+  /// Compares the derivatives of the signals rather than the signals
+  /// themselves (derivative DTW). Each level of the downsample pyramid is
+  /// derived on its own.
+  pub fn derivatives(&mut self) -> &mut Self
+  where
+    SampleType: Derivative,
+  {
+    self.derive_fn = Some(derived::<SampleType>);
+    self
+  }
+
+  /// Splits the solver into its cost map and the guide to solve it with,
+  /// which borrows the loss function.
+  #[inline]
+  #[allow(clippy::type_complexity)]
+  fn split(
+    &mut self,
+  ) -> (
+    &mut CostMap<C, Vec<MapCell<C>>, Vec<Row>>,
+    Guide<'_, LossFn>,
+  ) {
+    let guide = Guide {
+      loss_fn: &self.loss_fn,
+      factor: self.downsample_factor,
+      radius: self.radius,
+      constraint: self.constraint,
+      phase_weight: self.phase_weight,
+      refine: self.refine,
+    };
+    (&mut self.cost_map, guide)
+  }
+
+  /// Shrinks a signal until it's too short to shrink again, or until it's
+  /// been shrunk `downsample_limit` times. The smallest downsample comes
+  /// last.
+  #[inline]
+  fn downsample_all(&self, signal: &[SampleType]) -> Vec<Vec<SampleType>> {
+    let mut downsamples: Vec<Vec<SampleType>> = Vec::new();
+
+    while self
+      .downsample_limit
+      .map_or(true, |limit| downsamples.len() < limit)
+    {
+      let sig = downsamples.last().map_or(signal, |ds| &ds[..]);
+      let mut ds = Vec::with_capacity(sig.len() / self.downsample_factor);
+      if !shrink(
+        sig,
+        self.downsample_factor,
+        &self.downsample_fn,
+        |_, sample| ds.push(sample),
+      ) {
+        break;
+      }
+      downsamples.push(ds);
+    }
+
+    downsamples
+  }
+
+  /// Downsamples the signals and hands the levels of the pyramid to
+  /// `solve`, along with the cost map and guide to solve them with. With
+  /// `derivatives`, the levels are replaced by their derivatives first.
+  #[inline]
+  #[allow(clippy::type_complexity)]
+  fn solve_levels<R>(
+    &mut self,
+    solve: impl FnOnce(
+      &mut CostMap<C, Vec<MapCell<C>>, Vec<Row>>,
+      &[(&[SampleType], &[SampleType])],
+      &Guide<LossFn>,
+    ) -> Result<R, DtwError>,
+  ) -> Result<R, DtwError> {
+    let mut downsamples_y = self.downsample_all(self.sig_y);
+    let mut downsamples_x = self.downsample_all(self.sig_x);
+
+    let derivatives = self.derive_fn.map(|derive_fn| {
+      for ds in downsamples_y.iter_mut().chain(downsamples_x.iter_mut()) {
+        *ds = derive_fn(ds);
+      }
+      (derive_fn(self.sig_y), derive_fn(self.sig_x))
+    });
+    let (sig_y, sig_x) = match &derivatives {
+      Some((derivative_y, derivative_x)) => (&derivative_y[..], &derivative_x[..]),
+      None => (self.sig_y, self.sig_x),
+    };
+
+    let levels = heap_levels(sig_y, sig_x, &downsamples_y, &downsamples_x);
+    let (cost_map, guide) = self.split();
+    solve(cost_map, &levels, &guide)
+  }
+
+  #[inline]
+  pub fn solve(&mut self) -> Vec<PathPoint<C>> {
+    or_panic(self.try_solve())
+  }
+
+  /// Like `solve`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_solve(&mut self) -> Result<Vec<PathPoint<C>>, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| pyramid::solve(cost_map, levels, guide))
+  }
+
+  /// Like `solve`, but also returns the distance between the signals.
+  #[inline]
+  pub fn align(&mut self) -> DtwResult<Vec<PathPoint<C>>, C> {
+    or_panic(self.try_align())
+  }
+
+  /// Like `align`, but returns an error rather than panicking if the path
+  /// can't be found.
+  #[inline]
+  pub fn try_align(&mut self) -> Result<DtwResult<Vec<PathPoint<C>>, C>, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| pyramid::align(cost_map, levels, guide))
+  }
+
+  /// The distance between the signals along the path `solve` would find,
+  /// without tracing the path itself.
+  #[inline]
+  pub fn distance(&mut self) -> C {
+    or_panic(self.try_distance())
+  }
+
+  /// Like `distance`, but returns an error rather than panicking if the
+  /// distance can't be found.
+  #[inline]
+  pub fn try_distance(&mut self) -> Result<C, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| {
+      pyramid::distance::<_, _, _, _, Vec<PathPoint<C>>>(cost_map, levels, guide)
+    })
+  }
+
+  /// Like `distance`, but gives up as soon as every path left on the last
+  /// level is lossier than `best_so_far`. Losses must not be negative.
+  #[inline]
+  pub fn distance_within(&mut self, best_so_far: C) -> DistanceOutcome<C> {
+    or_panic(self.try_distance_within(best_so_far))
+  }
+
+  /// Like `distance_within`, but returns an error rather than panicking if
+  /// the distance can't be found.
+  #[inline]
+  pub fn try_distance_within(&mut self, best_so_far: C) -> Result<DistanceOutcome<C>, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| {
+      pyramid::distance_within::<_, _, _, _, Vec<PathPoint<C>>>(
+        cost_map,
+        levels,
+        guide,
+        best_so_far,
+      )
+    })
+  }
+
+  /// Solves the signals both guided and exactly, and reports how far the
+  /// guided solve landed from the optimal path. See `DtwSolver::evaluate`.
+  /// The maps grow to hold every cell of the grid (or every cell inside of
+  /// the global constraint) for the exact solve, and keep that size for the
+  /// solves after it.
+  #[inline]
+  pub fn evaluate(&mut self) -> Evaluation<C> {
+    or_panic(self.try_evaluate())
+  }
+
+  /// Like `evaluate`, but returns an error rather than panicking if either
+  /// solve fails.
+  #[inline]
+  pub fn try_evaluate(&mut self) -> Result<Evaluation<C>, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| {
+      evaluation::evaluate::<_, _, _, _, Vec<PathPoint<C>>>(cost_map, levels, guide)
+    })
+  }
+
+  /// Finds where `sig_y` best matches inside of the longer `sig_x`. The
+  /// match may start and end anywhere along `sig_x`, and each level of the
+  /// downsample pyramid only searches near the match found on the level
+  /// below it. Global constraints don't apply to subsequence matches.
+  #[inline]
+  pub fn solve_subsequence(&mut self) -> Subsequence<Vec<PathPoint<C>>, C> {
+    or_panic(self.try_solve_subsequence())
+  }
+
+  /// Like `solve_subsequence`, but returns an error rather than panicking
+  /// if the match can't be found.
+  #[inline]
+  pub fn try_solve_subsequence(&mut self) -> Result<Subsequence<Vec<PathPoint<C>>, C>, DtwError> {
+    self.solve_levels(|cost_map, levels, guide| pyramid::solve_subsequence(cost_map, levels, guide))
+  }
+}
+
+/// Lines up the downsamples of both signals into the levels of a pyramid,
+/// smallest first, ending with the signals themselves. Only the levels that
+/// exist for both signals are usable.
+#[inline]
+#[allow(clippy::type_complexity)]
+fn heap_levels<'a, SampleType>(
+  sig_y: &'a [SampleType],
+  sig_x: &'a [SampleType],
+  downsamples_y: &'a [Vec<SampleType>],
+  downsamples_x: &'a [Vec<SampleType>],
+) -> Vec<(&'a [SampleType], &'a [SampleType])> {
+  let ds_levels = core::cmp::min(downsamples_y.len(), downsamples_x.len());
+  let mut levels: Vec<(&[SampleType], &[SampleType])> = (0..ds_levels)
+    .rev()
+    .map(|i| (&downsamples_y[i][..], &downsamples_x[i][..]))
+    .collect();
+  levels.push((sig_y, sig_x));
+  levels
+}
+
+/// Something that returns the derivative of a signal, like `derived`.
+type DerivedFn<SampleType> = fn(&[SampleType]) -> Vec<SampleType>;
+
+/// The derivative of a signal, in a new `Vec`.
+#[inline]
+fn derived<SampleType: Derivative>(signal: &[SampleType]) -> Vec<SampleType> {
+  let mut derivative = signal.to_vec();
+  derive(signal, &mut derivative);
+  derivative
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::{downsample_fns, loss_fns, DtwSolver};
+  use rand::{Rng, SeedableRng};
+
+  #[test]
+  fn matches_stack_solver() {
+    let sig_y = [
+      0f32, 1f32, 5f32, 3f32, 2f32, 1f32, 0f32, 4f32, 2f32, 3f32, 2f32, 1f32, 0f32,
+    ];
+    let sig_x = [
+      3f32, 2f32, 3f32, 1f32, 0f32, 2f32, 0f32, 1f32, 5f32, 1f32, 3f32, 0f32, 2f32, 2f32, 4f32,
+      0f32, 1f32, 2f32,
+    ];
+
+    for radius in [0, 1, 3].iter() {
+      let stack =
+        DtwSolver::<f32, 13, 18, 31>::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+          .search_radius(*radius)
+          .constrain(GlobalConstraint::SakoeChibaBand { width: 8 })
+          .solve();
+      let heap = VecDtwSolver::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(*radius)
+        .constrain(GlobalConstraint::SakoeChibaBand { width: 8 })
+        .solve();
+
+      assert_eq!(stack.iter().count(), heap.len());
+      for (s, h) in stack.iter().zip(heap.iter()) {
+        assert_eq!(s.to_parent, h.to_parent);
+        assert_eq!(s.loss, h.loss);
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_empty_signals() {
+    let sig_y = [1f32, 2f32, 3f32];
+    let mut solver = VecDtwSolver::new(&sig_y, &[], downsample_fns::mean_u8, loss_fns::dist);
+
+    assert_eq!(DtwError::SignalTooShort, solver.try_solve().unwrap_err());
+    assert_eq!(DtwError::SignalTooShort, solver.try_align().unwrap_err());
+    assert_eq!(DtwError::SignalTooShort, solver.try_distance().unwrap_err());
+    assert_eq!(
+      DtwError::SignalTooShort,
+      solver.try_distance_within(0f32).unwrap_err()
+    );
+    assert_eq!(
+      DtwError::SignalTooShort,
+      solver.try_solve_subsequence().unwrap_err()
+    );
+  }
+
+  #[test]
+  fn reuses_maps_for_new_signals() {
+    let long_y = [0f32; 4096];
+    let long_x = [0f32; 3000];
+    let (short_y, short_x) = ([1f32, 2f32, 3f32], [1f32, 3f32]);
+
+    let mut solver = VecDtwSolver::new(&long_y, &long_x, downsample_fns::mean_u8, loss_fns::dist);
+    assert_eq!(0f32, solver.search_radius(1).solve()[0].loss);
+
+    let path = solver.signals(&short_y, &short_x).solve();
+    assert_eq!(1f32, path[0].loss);
+    assert_eq!(2, path.len());
+  }
+
+  #[test]
+  fn refines_within_exactly_sized_maps() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(23);
+
+    for _ in 0..100 {
+      let sig_y: Vec<f32> = (0..96).map(|_| rng.gen_range(-5f32, 5f32)).collect();
+      let sig_x: Vec<f32> = (0..80).map(|_| rng.gen_range(-5f32, 5f32)).collect();
+
+      let exact = VecDtwSolver::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .limit_downsamples(0)
+        .distance();
+      let refined = VecDtwSolver::new(&sig_y, &sig_x, downsample_fns::mean_u8, loss_fns::dist)
+        .search_radius(1)
+        .refine(2)
+        .align();
+
+      assert!(exact <= refined.distance);
+      assert_eq!(refined.distance, refined.path[0].loss);
+    }
+  }
+}
@@ -0,0 +1,315 @@
+use crate::error::DtwError;
+use crate::path::{Move, PathPoint};
+use crate::storage::Storage;
+
+/// One row of a window: the inclusive range of columns it covers, where its
+/// cells start in the packed cell storage, and the columns `mark` has asked
+/// for it to be widened to.
+#[derive(Clone, Copy, Debug)]
+pub struct Row {
+  min_x: usize,
+  max_x: usize,
+  start: usize,
+  marked: (usize, usize),
+}
+impl Row {
+  pub const EMPTY: Row = Row {
+    min_x: usize::MAX,
+    max_x: 0,
+    start: 0,
+    marked: (usize::MAX, 0),
+  };
+}
+
+/// The cells of the loss and path maps that get evaluated, stored as an
+/// inclusive range of columns for each row. Cells outside of the window are
+/// treated as if their loss were infinite, and aren't stored at all: the
+/// cells of each row are packed in right after those of the row before it.
+#[derive(Debug)]
+pub struct Window<Rows: Storage<Row>> {
+  rows: Rows,
+}
+impl<Rows: Storage<Row>> Window<Rows> {
+  #[inline]
+  pub const fn new(rows: Rows) -> Window<Rows> {
+    Window { rows }
+  }
+
+  /// Replaces the window with `y_size` rows, taking the range of columns
+  /// covered by each row from `row`.
+  #[inline]
+  pub fn fill(&mut self, y_size: usize, row: impl Fn(usize) -> (usize, usize)) {
+    self.rows.make_room(y_size, Row::EMPTY);
+    let rows = self.rows.items_mut();
+    for (y, r) in rows.iter_mut().take(y_size).enumerate() {
+      let (min_x, max_x) = row(y);
+      *r = Row {
+        min_x,
+        max_x,
+        ..Row::EMPTY
+      };
+    }
+  }
+
+  /// Covers every cell of a `y_size` by `x_size` grid.
+  #[inline]
+  pub fn full(&mut self, y_size: usize, x_size: usize) {
+    self.fill(y_size, |_| (0, x_size - 1));
+  }
+
+  /// Projects a path found on the downsampled signals onto the grid of the
+  /// signals they were shrunk from by `factor`. The path starts from
+  /// `ds_start_x` on the first row of the downsample. Every downsampled cell
+  /// covers a `factor` by `factor` block, except that the last row and
+  /// column of blocks absorb the leftover samples when an upsampled length
+  /// doesn't divide evenly. Fails if the path leaves the downsampled grid.
+  pub fn project<C>(
+    &mut self,
+    downsample_path: &[PathPoint<C>],
+    ds_start_x: usize,
+    factor: usize,
+    y_size: usize,
+    x_size: usize,
+  ) -> Result<(), DtwError> {
+    self.fill(y_size, |_| (usize::MAX, 0));
+    let (ds_y_size, ds_x_size) = (y_size / factor, x_size / factor);
+    if ds_start_x >= ds_x_size {
+      return Err(DtwError::InvalidGuidePath);
+    }
+
+    // Coordinates of the current path step on the downsample
+    let mut ds_y = 0;
+    let mut ds_x = ds_start_x;
+    self.cover_block(
+      upsample_span(ds_y, ds_y_size, y_size, factor),
+      upsample_span(ds_x, ds_x_size, x_size, factor),
+    );
+
+    for path_move in downsample_path.iter() {
+      if path_move.to_parent == Move::Stop {
+        break;
+      }
+
+      let (ds_dy, ds_dx) = path_move.to_parent.offset();
+      let (last_y, last_x) = (ds_y, ds_x);
+      ds_y += ds_dy;
+      ds_x += ds_dx;
+      if ds_y >= ds_y_size || ds_x >= ds_x_size {
+        return Err(DtwError::InvalidGuidePath);
+      }
+
+      match path_move.to_parent {
+        // Longer steps pass through cells between the two they join, so
+        // cover everything in between.
+        Move::Step { .. } => self.cover_block(
+          (
+            upsample_span(last_y, ds_y_size, y_size, factor).0,
+            upsample_span(ds_y, ds_y_size, y_size, factor).1,
+          ),
+          (
+            upsample_span(last_x, ds_x_size, x_size, factor).0,
+            upsample_span(ds_x, ds_x_size, x_size, factor).1,
+          ),
+        ),
+        _ => self.cover_block(
+          upsample_span(ds_y, ds_y_size, y_size, factor),
+          upsample_span(ds_x, ds_x_size, x_size, factor),
+        ),
+      };
+    }
+
+    // A path that stops short of the last row leaves the rows above it
+    // empty, with nothing to connect them.
+    if ds_y != ds_y_size - 1 {
+      return Err(DtwError::InvalidGuidePath);
+    }
+
+    // Blocks joined by a diagonal move only touch at their corners. Widen
+    // the rows on either side of the corner by one cell so the path has
+    // somewhere to go other than straight through it.
+    let rows = self.rows.items_mut();
+    for y in 1..y_size {
+      if rows[y].min_x > rows[y - 1].max_x {
+        rows[y].min_x -= 1;
+        rows[y - 1].max_x += 1;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Covers the cells along a path on the grid itself, rather than on a
+  /// downsample of it. The path starts from `start_x` on the first row, and
+  /// each move covers the block of cells between the two it joins. Fails if
+  /// the path leaves the grid or stops short of the last row.
+  pub fn trace<C>(
+    &mut self,
+    path: &[PathPoint<C>],
+    start_x: usize,
+    y_size: usize,
+    x_size: usize,
+  ) -> Result<(), DtwError> {
+    self.fill(y_size, |_| (usize::MAX, 0));
+    if start_x >= x_size {
+      return Err(DtwError::InvalidGuidePath);
+    }
+
+    let (mut y, mut x) = (0, start_x);
+    self.cover_block((y, y), (x, x));
+    for point in path.iter() {
+      if point.to_parent == Move::Stop {
+        break;
+      }
+
+      let (dy, dx) = point.to_parent.offset();
+      let (last_y, last_x) = (y, x);
+      y += dy;
+      x += dx;
+      if y >= y_size || x >= x_size {
+        return Err(DtwError::InvalidGuidePath);
+      }
+      self.cover_block((last_y, y), (last_x, x));
+    }
+
+    match y == y_size - 1 {
+      true => Ok(()),
+      false => Err(DtwError::InvalidGuidePath),
+    }
+  }
+
+  /// Widens the window by `radius` cells in every direction, so that any
+  /// cell within `radius` rows and columns of a covered cell gets covered.
+  ///
+  /// Both ends of the rows have to move monotonically to the right, as they
+  /// do for a projected path. The row `radius` above then always holds the
+  /// furthest left column nearby, and the row `radius` below the furthest
+  /// right, so the window can be widened in place.
+  pub fn expand(&mut self, radius: usize, y_size: usize, x_size: usize) {
+    if radius == 0 {
+      return;
+    }
+
+    let rows = self.rows.items_mut();
+
+    // Each row's left end comes from a row above it, and its right end from
+    // a row below it, so they're updated in opposite directions.
+    for y in (0..y_size).rev() {
+      rows[y].min_x = rows[y.saturating_sub(radius)].min_x.saturating_sub(radius);
+    }
+    for y in 0..y_size {
+      let near_y = core::cmp::min(y + radius, y_size - 1);
+      rows[y].max_x = core::cmp::min(rows[near_y].max_x + radius, x_size - 1);
+    }
+  }
+
+  /// Narrows the window down to the cells inside of `bounds`, which gives
+  /// the range of columns allowed in each row. Returns `false` if what's
+  /// left no longer connects one corner to the other, in which case the
+  /// window shouldn't be used.
+  pub fn intersect(&mut self, y_size: usize, bounds: impl Fn(usize) -> (usize, usize)) -> bool {
+    let rows = self.rows.items_mut();
+    for y in 0..y_size {
+      let (min_x, max_x) = bounds(y);
+      rows[y].min_x = core::cmp::max(rows[y].min_x, min_x);
+      rows[y].max_x = core::cmp::min(rows[y].max_x, max_x);
+
+      if rows[y].min_x > rows[y].max_x {
+        return false;
+      }
+      if y > 0 && rows[y].min_x > rows[y - 1].max_x + 1 {
+        return false;
+      }
+    }
+
+    true
+  }
+
+  /// Marks the rows `y_span` to be widened to the columns `x_span` by
+  /// `cover_marked`. The window itself stays as it is until then, so the
+  /// cells of the last solve can still be looked up.
+  #[inline]
+  pub fn mark(&mut self, y_span: (usize, usize), x_span: (usize, usize)) {
+    let rows = self.rows.items_mut();
+    for row in rows[y_span.0..=y_span.1].iter_mut() {
+      row.marked.0 = core::cmp::min(row.marked.0, x_span.0);
+      row.marked.1 = core::cmp::max(row.marked.1, x_span.1);
+    }
+  }
+
+  /// Widens the first `y_size` rows to cover the columns they were marked
+  /// with, and packs them again. Returns `false` if every marked column was
+  /// already covered.
+  pub fn cover_marked(&mut self, y_size: usize) -> bool {
+    let mut widened = false;
+    for row in self.rows.items_mut()[..y_size].iter_mut() {
+      let (min_x, max_x) = row.marked;
+      if min_x <= max_x && (min_x < row.min_x || max_x > row.max_x) {
+        row.min_x = core::cmp::min(row.min_x, min_x);
+        row.max_x = core::cmp::max(row.max_x, max_x);
+        widened = true;
+      }
+      row.marked = Row::EMPTY.marked;
+    }
+
+    self.pack(y_size);
+    widened
+  }
+
+  /// Stretches the rows `y_span` to cover the columns `x_span` too.
+  #[inline]
+  fn cover_block(&mut self, y_span: (usize, usize), x_span: (usize, usize)) {
+    let rows = self.rows.items_mut();
+    for row in rows[y_span.0..=y_span.1].iter_mut() {
+      row.min_x = core::cmp::min(row.min_x, x_span.0);
+      row.max_x = core::cmp::max(row.max_x, x_span.1);
+    }
+  }
+
+  /// Lays the rows out one after another in the cell storage. Returns how
+  /// many cells the window covers in total, which is how many need to be
+  /// stored.
+  #[inline]
+  pub fn pack(&mut self, y_size: usize) -> usize {
+    let mut len = 0;
+    for row in self.rows.items_mut()[..y_size].iter_mut() {
+      row.start = len;
+      if row.min_x <= row.max_x {
+        len += row.max_x - row.min_x + 1;
+      }
+    }
+    len
+  }
+
+  /// Where the cell at `y`, `x` is kept in the packed cell storage. The cell
+  /// has to be inside of the window.
+  #[inline]
+  pub fn index(&self, y: usize, x: usize) -> usize {
+    let row = &self.rows.items()[y];
+    row.start + x - row.min_x
+  }
+
+  /// The inclusive range of columns covered in row `y`.
+  #[inline]
+  pub fn row(&self, y: usize) -> (usize, usize) {
+    let row = &self.rows.items()[y];
+    (row.min_x, row.max_x)
+  }
+
+  #[inline]
+  pub fn contains(&self, y: usize, x: usize) -> bool {
+    let (min_x, max_x) = self.row(y);
+    x >= min_x && x <= max_x
+  }
+}
+
+/// The inclusive range of upsampled indices covered by downsampled index
+/// `ds_t`, of a signal shrunk by `factor`. The last downsampled index also
+/// covers the leftover samples of an upsample that doesn't divide evenly.
+#[inline]
+fn upsample_span(ds_t: usize, ds_size: usize, size: usize, factor: usize) -> (usize, usize) {
+  let start = ds_t * factor;
+  match ds_t == ds_size - 1 {
+    true => (start, size - 1),
+    false => (start, start + factor - 1),
+  }
+}